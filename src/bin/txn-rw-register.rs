@@ -0,0 +1,11 @@
+use anyhow::Result;
+
+use dist_sys_challenge::workloads::txn_rw_register::{TxnConfig, TxnRwRegister};
+use dist_sys_challenge::*;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> Result<()> {
+    Runtime::new(TxnRwRegister::new(TxnConfig::from_env()?))
+        .run()
+        .await
+}
@@ -0,0 +1,45 @@
+//! Checkers that verify the properties each workload requires on a recorded [`History`].
+//!
+//! Histories can come from the [`sim`](crate::sim)ulator ([`History::from_trace`]) or from
+//! the `history.txt`/`history.edn` files that Maelstrom stores for every test ([`History::parse`]).
+//! Each checker returns the [`Anomaly`]s it found, so an empty list means the history is valid.
+
+use std::fmt;
+
+mod edn;
+mod history;
+#[cfg(feature = "sim")]
+mod trace;
+
+pub mod broadcast;
+pub mod g_counter;
+pub mod kafka;
+pub mod register;
+
+pub use history::{History, Op, OpType, Operation};
+
+/// A violation of a workload's properties.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Anomaly {
+    /// The position in the history of the invocation of the offending operation, if there is one.
+    pub index: Option<usize>,
+    pub description: String,
+}
+
+impl Anomaly {
+    fn new(index: impl Into<Option<usize>>, description: impl Into<String>) -> Self {
+        Self {
+            index: index.into(),
+            description: description.into(),
+        }
+    }
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.index {
+            Some(index) => write!(f, "operation {index}: {}", self.description),
+            None => write!(f, "{}", self.description),
+        }
+    }
+}
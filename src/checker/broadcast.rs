@@ -0,0 +1,72 @@
+use std::collections::BTreeSet;
+
+use serde_json::Value;
+
+use super::{Anomaly, History, OpType};
+
+/// Check that every acknowledged broadcast is eventually read, and that no read returns
+/// a value that was never broadcast.
+///
+/// Values take a while to reach all nodes, so only the final reads have to contain every
+/// acknowledged value: the reads invoked at or after `settled`, the index in the history by which
+/// the network has healed and the broadcasts had time to spread, and after all acknowledged
+/// broadcasts have completed. With a `settled` of 0, that's every read after the last acknowledgement.
+pub fn check(history: &History, settled: usize) -> Vec<Anomaly> {
+    let operations = history.operations();
+    let broadcasts = operations.iter().filter(|op| op.f == "broadcast");
+    let attempted: BTreeSet<u64> = broadcasts
+        .clone()
+        .filter(|op| op.outcome != OpType::Fail)
+        .filter_map(|op| op.input.as_u64())
+        .collect();
+    let acked: BTreeSet<u64> = broadcasts
+        .clone()
+        .filter(|op| op.outcome == OpType::Ok)
+        .filter_map(|op| op.input.as_u64())
+        .collect();
+    let last_ack = broadcasts
+        .filter(|op| op.outcome == OpType::Ok)
+        .filter_map(|op| op.complete)
+        .max();
+
+    let mut anomalies = Vec::new();
+    let mut final_reads = 0;
+    let reads = operations
+        .iter()
+        .filter(|op| op.f == "read" && op.outcome == OpType::Ok);
+    for read in reads {
+        let Some(values) = read.output.and_then(Value::as_array) else {
+            anomalies.push(Anomaly::new(read.invoke, "read returned no list of values"));
+            continue;
+        };
+        let values: BTreeSet<u64> = values.iter().filter_map(Value::as_u64).collect();
+        let unexpected: Vec<_> = values.difference(&attempted).collect();
+        if !unexpected.is_empty() {
+            anomalies.push(Anomaly::new(
+                read.invoke,
+                format!("read values that were never broadcast: {unexpected:?}"),
+            ));
+        }
+        if read.invoke < settled || last_ack.is_some_and(|last_ack| read.invoke < last_ack) {
+            continue;
+        }
+        final_reads += 1;
+        let missing: Vec<_> = acked.difference(&values).collect();
+        if !missing.is_empty() {
+            anomalies.push(Anomaly::new(
+                read.invoke,
+                format!(
+                    "final read by {} is missing acknowledged values: {missing:?}",
+                    read.process
+                ),
+            ));
+        }
+    }
+    if final_reads == 0 && !acked.is_empty() {
+        anomalies.push(Anomaly::new(
+            None,
+            "no final reads after the last acknowledged broadcast",
+        ));
+    }
+    anomalies
+}
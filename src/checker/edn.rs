@@ -0,0 +1,160 @@
+//! Just enough of an EDN parser to read the histories that Maelstrom writes.
+
+use std::{iter::Peekable, str::Chars};
+
+use anyhow::{bail, Context, Result};
+use serde_json::{Map, Number, Value};
+
+/// Parse a single EDN value into JSON.
+///
+/// Keywords and symbols become strings (without the leading `:`), lists and sets become arrays,
+/// and map keys that aren't strings are replaced by their JSON representation.
+/// Tags like `#jepsen.history.Op` are ignored.
+pub(crate) fn parse(input: &str) -> Result<Value> {
+    let mut parser = Parser {
+        chars: input.chars().peekable(),
+    };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if let Some(c) = parser.chars.peek() {
+        bail!("unexpected {c:?} after the end of the value");
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if c == ';' {
+                // Comments run until the end of the line.
+                while self.chars.next_if(|&c| c != '\n').is_some() {}
+            } else if c.is_whitespace() || c == ',' {
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value> {
+        self.skip_whitespace();
+        let Some(&c) = self.chars.peek() else {
+            bail!("unexpected end of input");
+        };
+        match c {
+            '[' | '(' => {
+                self.chars.next();
+                self.sequence(if c == '[' { ']' } else { ')' })
+            }
+            '{' => {
+                self.chars.next();
+                self.map()
+            }
+            '#' => {
+                self.chars.next();
+                if self.chars.next_if_eq(&'{').is_some() {
+                    return self.sequence('}');
+                }
+                // A tagged value, we only care about the value.
+                self.token();
+                self.value()
+            }
+            '"' => {
+                self.chars.next();
+                self.string()
+            }
+            ':' => {
+                self.chars.next();
+                Ok(Value::String(self.token()))
+            }
+            _ => {
+                let token = self.token();
+                if token.is_empty() {
+                    bail!("unexpected {c:?}");
+                }
+                Ok(match token.as_str() {
+                    "nil" => Value::Null,
+                    "true" => Value::Bool(true),
+                    "false" => Value::Bool(false),
+                    _ => number(&token).unwrap_or(Value::String(token)),
+                })
+            }
+        }
+    }
+
+    fn sequence(&mut self, end: char) -> Result<Value> {
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.chars.next_if_eq(&end).is_some() {
+                return Ok(Value::Array(values));
+            }
+            values.push(self.value()?);
+        }
+    }
+
+    fn map(&mut self) -> Result<Value> {
+        let mut map = Map::new();
+        loop {
+            self.skip_whitespace();
+            if self.chars.next_if_eq(&'}').is_some() {
+                return Ok(Value::Object(map));
+            }
+            let key = match self.value()? {
+                Value::String(key) => key,
+                key => key.to_string(),
+            };
+            let value = self
+                .value()
+                .with_context(|| format!("in the value of {key}"))?;
+            map.insert(key, value);
+        }
+    }
+
+    fn string(&mut self) -> Result<Value> {
+        let mut string = String::new();
+        loop {
+            match self.chars.next() {
+                Some('"') => return Ok(Value::String(string)),
+                Some('\\') => match self.chars.next() {
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some('r') => string.push('\r'),
+                    Some(c) => string.push(c),
+                    None => bail!("unterminated string"),
+                },
+                Some(c) => string.push(c),
+                None => bail!("unterminated string"),
+            }
+        }
+    }
+
+    /// Everything up to the next delimiter.
+    fn token(&mut self) -> String {
+        let mut token = String::new();
+        while let Some(c) = self
+            .chars
+            .next_if(|&c| !c.is_whitespace() && !"[](){}\",;".contains(c))
+        {
+            token.push(c);
+        }
+        token
+    }
+}
+
+fn number(token: &str) -> Option<Value> {
+    // Clojure marks big integers and decimals with a suffix.
+    let token = token.trim_end_matches(['N', 'M']);
+    if let Ok(n) = token.parse::<i64>() {
+        return Some(Value::from(n));
+    }
+    if let Ok(n) = token.parse::<u64>() {
+        return Some(Value::from(n));
+    }
+    let n = token.parse::<f64>().ok()?;
+    Number::from_f64(n).map(Value::Number)
+}
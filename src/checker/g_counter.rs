@@ -0,0 +1,41 @@
+use super::{Anomaly, History, OpType};
+
+/// Check that every read of the counter lies within the possible bounds:
+/// at least the sum of all adds that completed before the read started,
+/// and at most the sum of all adds that might have happened before the read ended.
+pub fn check(history: &History) -> Vec<Anomaly> {
+    let operations = history.operations();
+    let adds: Vec<_> = operations
+        .iter()
+        .filter(|op| op.f == "add" && op.outcome != OpType::Fail)
+        .filter_map(|op| Some((op, op.input.as_u64()?)))
+        .collect();
+
+    let mut anomalies = Vec::new();
+    let reads = operations
+        .iter()
+        .filter(|op| op.f == "read" && op.outcome == OpType::Ok);
+    for read in reads {
+        let Some(value) = read.output.and_then(|value| value.as_u64()) else {
+            anomalies.push(Anomaly::new(read.invoke, "read returned no number"));
+            continue;
+        };
+        let lower: u64 = adds
+            .iter()
+            .filter(|(add, _)| add.outcome == OpType::Ok && add.precedes(read))
+            .map(|(_, delta)| delta)
+            .sum();
+        let upper: u64 = adds
+            .iter()
+            .filter(|(add, _)| read.complete.map_or(true, |complete| add.invoke < complete))
+            .map(|(_, delta)| delta)
+            .sum();
+        if value < lower || value > upper {
+            anomalies.push(Anomaly::new(
+                read.invoke,
+                format!("read {value}, but the counter must have been within {lower}..={upper}"),
+            ));
+        }
+    }
+    anomalies
+}
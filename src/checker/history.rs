@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+
+use super::edn;
+
+/// The type of an [`Op`], as in Jepsen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpType {
+    /// A client started an operation.
+    Invoke,
+    /// The operation took place.
+    Ok,
+    /// The operation definitely did not take place.
+    Fail,
+    /// We don't know whether the operation took place.
+    Info,
+}
+
+/// A single entry of a [`History`]: the invocation or the completion of an operation.
+///
+/// Values follow the conventions of Maelstrom's workloads, e.g. a kafka `send` is invoked
+/// with `[["send", key, msg]]` and completes with `[["send", key, [offset, msg]]]`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Op {
+    pub process: String,
+    pub kind: OpType,
+    pub f: String,
+    pub value: Value,
+}
+
+/// An invocation together with its completion.
+#[derive(Debug, Clone, Copy)]
+pub struct Operation<'a> {
+    pub process: &'a str,
+    pub f: &'a str,
+    /// How the operation ended. Operations that never completed are [`OpType::Info`].
+    pub outcome: OpType,
+    /// The position of the invocation in the history.
+    pub invoke: usize,
+    /// The position of the completion in the history,
+    /// or `None` if the operation could have taken effect at any time after its invocation.
+    pub complete: Option<usize>,
+    /// The value of the invocation.
+    pub input: &'a Value,
+    /// The value of the completion, if there was one.
+    pub output: Option<&'a Value>,
+}
+
+impl Operation<'_> {
+    /// Whether this operation definitely ended before `other` started.
+    pub fn precedes(&self, other: &Operation) -> bool {
+        self.complete
+            .is_some_and(|complete| complete < other.invoke)
+    }
+}
+
+/// The invocations and completions of the client operations of a test, in the order in which they happened.
+#[derive(Debug, Clone, Default)]
+pub struct History {
+    ops: Vec<Op>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, op: Op) {
+        self.ops.push(op);
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// Pair every invocation with the next completion of the same process.
+    pub fn operations(&self) -> Vec<Operation<'_>> {
+        let mut operations = Vec::new();
+        let mut open: HashMap<&str, usize> = HashMap::new();
+        for (i, op) in self.ops.iter().enumerate() {
+            if op.kind == OpType::Invoke {
+                open.insert(&op.process, operations.len());
+                operations.push(Operation {
+                    process: &op.process,
+                    f: &op.f,
+                    outcome: OpType::Info,
+                    invoke: i,
+                    complete: None,
+                    input: &op.value,
+                    output: None,
+                });
+            } else if let Some(j) = open.remove(op.process.as_str()) {
+                let operation = &mut operations[j];
+                operation.outcome = op.kind;
+                operation.output = Some(&op.value);
+                if op.kind != OpType::Info {
+                    operation.complete = Some(i);
+                }
+            }
+        }
+        operations
+    }
+
+    /// Parse a history as written by Maelstrom, in either the `history.txt` or the `history.edn` format.
+    ///
+    /// Operations of the nemesis are skipped.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut history = Self::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let op = if line.starts_with('{') || line.starts_with('#') {
+                parse_edn_op(line)
+            } else {
+                parse_txt_op(line)
+            };
+            if let Some(op) = op.with_context(|| format!("line {}: {line}", i + 1))? {
+                history.push(op);
+            }
+        }
+        Ok(history)
+    }
+}
+
+/// Parse a line of `history.txt`: process, type, f and value, separated by tabs.
+fn parse_txt_op(line: &str) -> Result<Option<Op>> {
+    let mut fields = line.split('\t');
+    let process = fields.next().unwrap_or_default();
+    if process.starts_with(':') {
+        return Ok(None);
+    }
+    let (Some(kind), Some(f)) = (fields.next(), fields.next()) else {
+        bail!("expected at least process, type and f");
+    };
+    let value = match fields.next() {
+        Some(value) => edn::parse(value)?,
+        None => Value::Null,
+    };
+    Ok(Some(Op {
+        process: process.to_owned(),
+        kind: op_type(kind.trim_start_matches(':'))?,
+        f: f.trim_start_matches(':').to_owned(),
+        value,
+    }))
+}
+
+/// Parse a line of `history.edn`, which is a map with (at least) process, type, f and value.
+fn parse_edn_op(line: &str) -> Result<Option<Op>> {
+    let op = edn::parse(line)?;
+    let process = match &op["process"] {
+        Value::Number(n) => n.to_string(),
+        // The nemesis and other special processes are keywords.
+        _ => return Ok(None),
+    };
+    let (Some(kind), Some(f)) = (op["type"].as_str(), op["f"].as_str()) else {
+        bail!("expected type and f");
+    };
+    Ok(Some(Op {
+        process,
+        kind: op_type(kind)?,
+        f: f.to_owned(),
+        value: op["value"].clone(),
+    }))
+}
+
+fn op_type(kind: &str) -> Result<OpType> {
+    Ok(match kind {
+        "invoke" => OpType::Invoke,
+        "ok" => OpType::Ok,
+        "fail" => OpType::Fail,
+        "info" => OpType::Info,
+        _ => bail!("unknown operation type {kind}"),
+    })
+}
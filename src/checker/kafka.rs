@@ -0,0 +1,176 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde_json::Value;
+
+use super::{Anomaly, History, OpType, Operation};
+
+/// A message that a send or a poll saw at an offset.
+struct Record<'a> {
+    op: &'a Operation<'a>,
+    key: String,
+    offset: u64,
+    msg: &'a Value,
+    /// The message key of a send, if it has one.
+    msg_key: Option<&'a Value>,
+}
+
+/// Check the kafka workload:
+/// - every offset of a key holds a single message,
+/// - a send that starts after another send of the same key completed gets a higher offset,
+/// - polls return the messages of a key in increasing offset order,
+/// - polls don't skip over acknowledged sends (which would be lost writes),
+/// - and no message of a key shows up at two different offsets.
+///
+/// Offsets can have gaps, where aborted transactions and compaction removed messages.
+/// Sends can name their message key in a fourth element, `[:send key [offset msg] msg-key]`:
+/// skipping over those is fine if there's a later send with the same message key.
+pub fn check(history: &History) -> Vec<Anomaly> {
+    let operations = history.operations();
+    let mut anomalies = Vec::new();
+    let mut sends = Vec::new();
+    let mut polls: Vec<Vec<Record>> = Vec::new();
+    for op in operations.iter().filter(|op| op.outcome == OpType::Ok) {
+        let micro_ops = op.output.and_then(Value::as_array).into_iter().flatten();
+        for micro_op in micro_ops.filter_map(Value::as_array) {
+            let Some((f, args)) = micro_op.split_first() else {
+                anomalies.push(Anomaly::new(op.invoke, "malformed history: empty micro-op"));
+                continue;
+            };
+            match (f.as_str(), args) {
+                (Some("send"), [key, sent, msg_key @ ..]) => {
+                    match (sent.get(0).and_then(Value::as_u64), sent.get(1)) {
+                        (Some(offset), Some(msg)) => sends.push(Record {
+                            op,
+                            key: key_string(key),
+                            offset,
+                            msg,
+                            msg_key: msg_key.first(),
+                        }),
+                        _ => anomalies.push(Anomaly::new(op.invoke, "send returned no offset")),
+                    }
+                }
+                (Some("poll"), [Value::Object(msgs)]) => {
+                    for (key, records) in msgs {
+                        let records = records.as_array().into_iter().flatten();
+                        let poll = records
+                            .filter_map(|record| {
+                                Some(Record {
+                                    op,
+                                    key: key.clone(),
+                                    offset: record.get(0)?.as_u64()?,
+                                    msg: record.get(1)?,
+                                    msg_key: None,
+                                })
+                            })
+                            .collect();
+                        polls.push(poll);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Everything we learned about the contents of the logs.
+    let mut seen: HashMap<(&str, u64), &Value> = HashMap::new();
+    // And where each message is. JSON values can't be hashed, so they go by their text.
+    let mut offsets: HashMap<(&str, String), BTreeSet<u64>> = HashMap::new();
+    for record in sends.iter().chain(polls.iter().flatten()) {
+        let msg = seen
+            .entry((&record.key, record.offset))
+            .or_insert(record.msg);
+        if *msg != record.msg {
+            anomalies.push(Anomaly::new(
+                record.op.invoke,
+                format!(
+                    "offset {} of key {} holds both {} and {}",
+                    record.offset, record.key, msg, record.msg
+                ),
+            ));
+        }
+        let msg_offsets = offsets
+            .entry((&record.key, record.msg.to_string()))
+            .or_default();
+        if msg_offsets.insert(record.offset) && msg_offsets.len() > 1 {
+            let other = msg_offsets.iter().find(|&&offset| offset != record.offset);
+            let other = other.expect("there are at least two offsets");
+            anomalies.push(Anomaly::new(
+                record.op.invoke,
+                format!(
+                    "key {} holds {} at both offset {} and {}",
+                    record.key, record.msg, other, record.offset
+                ),
+            ));
+        }
+    }
+
+    let mut acked: HashMap<&str, BTreeMap<u64, &Record>> = HashMap::new();
+    for send in &sends {
+        acked
+            .entry(&send.key)
+            .or_default()
+            .insert(send.offset, send);
+    }
+    for sends in acked.values() {
+        for a in sends.values() {
+            for b in sends.values() {
+                if a.op.precedes(b.op) && a.offset >= b.offset {
+                    anomalies.push(Anomaly::new(
+                        b.op.invoke,
+                        format!(
+                            "send to key {} got offset {} after an earlier send got offset {}",
+                            b.key, b.offset, a.offset
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+
+    for poll in &polls {
+        for pair in poll.windows(2) {
+            let (a, b) = (&pair[0], &pair[1]);
+            if a.offset >= b.offset {
+                anomalies.push(Anomaly::new(
+                    a.op.invoke,
+                    format!(
+                        "poll of key {} returned offset {} before offset {}",
+                        a.key, a.offset, b.offset
+                    ),
+                ));
+                continue;
+            }
+            let Some(sends) = acked.get(a.key.as_str()) else {
+                continue;
+            };
+            let compacted = |send: &Record| {
+                send.msg_key.is_some_and(|msg_key| {
+                    sends
+                        .range(send.offset + 1..)
+                        .any(|(_, later)| later.msg_key == Some(msg_key))
+                })
+            };
+            let skipped = sends
+                .range(a.offset + 1..b.offset)
+                .filter(|(_, send)| !compacted(send));
+            for (offset, send) in skipped {
+                anomalies.push(Anomaly::new(
+                    a.op.invoke,
+                    format!(
+                        "poll of key {} skipped from offset {} to {}, losing {} at offset {offset}",
+                        a.key, a.offset, b.offset, send.msg
+                    ),
+                ));
+            }
+        }
+    }
+    anomalies
+}
+
+/// Keys are strings on the wire, but Maelstrom's histories use numbers.
+fn key_string(key: &Value) -> String {
+    match key {
+        Value::String(key) => key.clone(),
+        key => key.to_string(),
+    }
+}
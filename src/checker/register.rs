@@ -0,0 +1,126 @@
+use std::collections::{BTreeMap, HashSet};
+
+use serde_json::Value;
+
+use super::{Anomaly, History, OpType};
+
+enum Kind {
+    Read(Value),
+    Write(Value),
+    Cas(Value, Value),
+}
+
+struct RegisterOp {
+    invoke: usize,
+    /// `None` for operations with an unknown outcome, which may take effect at any time
+    /// after their invocation, or not at all.
+    complete: Option<usize>,
+    kind: Kind,
+}
+
+impl RegisterOp {
+    /// The value of the register after applying this operation to `state`,
+    /// or `None` if the operation can't take place in that state.
+    fn apply(&self, state: &Value) -> Option<Value> {
+        match &self.kind {
+            Kind::Read(value) => (value == state).then(|| state.clone()),
+            Kind::Write(value) => Some(value.clone()),
+            Kind::Cas(from, to) => (from == state).then(|| to.clone()),
+        }
+    }
+}
+
+/// Check that the reads, writes and CAS operations on every key of a KV store are linearizable.
+///
+/// Operations take the form of Maelstrom's `lin-kv` workload: `read` with `[key, value]`,
+/// `write` with `[key, value]` and `cas` with `[key, [from, to]]`. Keys start out as `null`.
+/// Each key is checked on its own with the algorithm of Wing and Gong, as refined by Lowe,
+/// i.e. a search over all orders of the concurrent operations with memoization of visited states.
+pub fn check(history: &History) -> Vec<Anomaly> {
+    let mut keys: BTreeMap<String, Vec<RegisterOp>> = BTreeMap::new();
+    for op in history.operations() {
+        let (Some(key), Some(value)) = (op.input.get(0), op.input.get(1)) else {
+            continue;
+        };
+        let kind = match (op.f, op.outcome) {
+            (_, OpType::Fail) => continue,
+            // A read without a result doesn't tell us anything.
+            ("read", OpType::Ok) => match op.output.and_then(|output| output.get(1)) {
+                Some(value) => Kind::Read(value.clone()),
+                None => continue,
+            },
+            ("write", _) => Kind::Write(value.clone()),
+            ("cas", _) => match (value.get(0), value.get(1)) {
+                (Some(from), Some(to)) => Kind::Cas(from.clone(), to.clone()),
+                _ => continue,
+            },
+            _ => continue,
+        };
+        keys.entry(key.to_string()).or_default().push(RegisterOp {
+            invoke: op.invoke,
+            complete: op.complete.filter(|_| op.outcome == OpType::Ok),
+            kind,
+        });
+    }
+
+    keys.into_iter()
+        .filter(|(_, ops)| !Search::new(ops).run(&Value::Null))
+        .map(|(key, ops)| {
+            Anomaly::new(
+                ops.first().map(|op| op.invoke),
+                format!("the operations on key {key} are not linearizable"),
+            )
+        })
+        .collect()
+}
+
+struct Search<'a> {
+    /// Sorted by invocation.
+    ops: &'a [RegisterOp],
+    linearized: Vec<bool>,
+    /// The combinations of linearized operations and register values that we already explored.
+    visited: HashSet<(Vec<bool>, String)>,
+}
+
+impl<'a> Search<'a> {
+    fn new(ops: &'a [RegisterOp]) -> Self {
+        Self {
+            ops,
+            linearized: vec![false; ops.len()],
+            visited: HashSet::new(),
+        }
+    }
+
+    /// Whether the remaining operations can be linearized, starting from `state`.
+    fn run(&mut self, state: &Value) -> bool {
+        let pending = || {
+            self.ops
+                .iter()
+                .zip(&self.linearized)
+                .filter(|(_, &linearized)| !linearized)
+        };
+        // Operations with an unknown outcome are optional, so we're done once all others are linearized.
+        // The next operation has to start before the first of the remaining ones completes.
+        let Some(deadline) = pending().filter_map(|(op, _)| op.complete).min() else {
+            return true;
+        };
+        let candidates: Vec<usize> = (0..self.ops.len())
+            .filter(|&i| !self.linearized[i] && self.ops[i].invoke < deadline)
+            .collect();
+        for i in candidates {
+            let Some(next) = self.ops[i].apply(state) else {
+                continue;
+            };
+            self.linearized[i] = true;
+            if self
+                .visited
+                .insert((self.linearized.clone(), next.to_string()))
+                && self.run(&next)
+            {
+                return true;
+            }
+            self.linearized[i] = false;
+        }
+        false
+    }
+}
@@ -0,0 +1,130 @@
+//! Turning the traces of the simulator into histories.
+
+use std::collections::HashMap;
+
+use serde_json::{json, Value};
+
+use super::{History, Op, OpType};
+use crate::sim::{Event, EventKind};
+use crate::ErrorCode;
+
+impl History {
+    /// Extract the operations of the simulator's clients from the trace of a simulated cluster.
+    ///
+    /// A request becomes an invocation when it is sent and its reply a completion when it is delivered.
+    /// Requests without a reply stay open, so they become [`OpType::Info`] operations.
+    pub fn from_trace(trace: &[Event]) -> Self {
+        let mut history = Self::new();
+        let mut pending = HashMap::new();
+        for event in trace {
+            let msg = &event.message;
+            if is_client(&msg.src) && !is_client(&msg.dst) && event.kind != EventKind::Delivered {
+                let Some((f, value)) = invocation(&msg.body.inner) else {
+                    continue;
+                };
+                if let Some(id) = msg.body.id {
+                    pending.insert((msg.src.as_str(), id), &msg.body.inner);
+                }
+                history.push(Op {
+                    process: msg.src.clone(),
+                    kind: OpType::Invoke,
+                    f,
+                    value,
+                });
+            } else if is_client(&msg.dst) && event.kind == EventKind::Delivered {
+                let Some(id) = msg.body.in_reply_to else {
+                    continue;
+                };
+                let Some(request) = pending.remove(&(msg.dst.as_str(), id)) else {
+                    continue;
+                };
+                let (f, _) = invocation(request).expect("only invocations are pending");
+                let (kind, value) = completion(request, &msg.body.inner);
+                history.push(Op {
+                    process: msg.dst.clone(),
+                    kind,
+                    f,
+                    value,
+                });
+            }
+        }
+        history
+    }
+}
+
+/// The simulator names its clients `c0`, `c1`, and so on.
+fn is_client(id: &str) -> bool {
+    id.strip_prefix('c')
+        .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// The `f` and value of the invocation for a request, or `None` for requests that aren't client operations.
+fn invocation(body: &Value) -> Option<(String, Value)> {
+    let f = body.get("type")?.as_str()?;
+    let value = match f {
+        "init" => return None,
+        "broadcast" => body["message"].clone(),
+        "add" => body["delta"].clone(),
+        "read" if body.get("key").is_some() => json!([body["key"], null]),
+        "read" => Value::Null,
+        "write" => json!([body["key"], body["value"]]),
+        "cas" => json!([body["key"], [body["from"], body["to"]]]),
+        "send" => json!([send(body, &body["msg"])]),
+        "send_txn" => body["msgs"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|msg| json!(["send", msg[0], msg[1]]))
+            .collect(),
+        "poll" => json!([["poll"]]),
+        _ => body.clone(),
+    };
+    Some((f.to_owned(), value))
+}
+
+/// The type and value of the completion for the `reply` to `request`.
+fn completion(request: &Value, reply: &Value) -> (OpType, Value) {
+    let (f, input) = invocation(request).expect("only invocations are pending");
+    if reply["type"] == "error" {
+        let code = reply["code"].as_u64().map_or(ErrorCode::Crash, |code| {
+            ErrorCode::from(u16::try_from(code).unwrap_or(u16::MAX))
+        });
+        // Maelstrom's clients treat reading a missing key as reading nil.
+        if f == "read" && code == ErrorCode::KeyDoesNotExist {
+            return (OpType::Ok, input);
+        }
+        let kind = if code.is_definite() {
+            OpType::Fail
+        } else {
+            OpType::Info
+        };
+        return (kind, input);
+    }
+    let value = match f.as_str() {
+        "read" if request.get("key").is_some() => json!([request["key"], reply["value"]]),
+        "read" => match reply.get("messages") {
+            Some(messages) => messages.clone(),
+            None => reply["value"].clone(),
+        },
+        "send" => json!([send(request, &json!([reply["offset"], request["msg"]]))]),
+        "send_txn" => request["msgs"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .zip(reply["offsets"].as_array().into_iter().flatten())
+            .map(|(msg, offset)| json!(["send", msg[0], [offset, msg[1]]]))
+            .collect(),
+        "poll" => json!([["poll", reply["msgs"]]]),
+        _ => input,
+    };
+    (OpType::Ok, value)
+}
+
+/// The micro-op of a send with `value`, followed by its message key if it has one,
+/// so that the checker knows which messages compaction may remove.
+fn send(request: &Value, value: &Value) -> Value {
+    match request.get("msg_key") {
+        Some(msg_key) => json!(["send", request["key"], value, msg_key]),
+        None => json!(["send", request["key"], value]),
+    }
+}
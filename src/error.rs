@@ -0,0 +1,155 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// The error codes defined by the Maelstrom protocol.
+///
+/// See: <https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors>
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(from = "u16", into = "u16")]
+pub enum ErrorCode {
+    /// The requested operation could not be completed in time.
+    Timeout,
+    /// The requested node does not exist.
+    NodeNotFound,
+    /// The requested operation is not supported by this node.
+    NotSupported,
+    /// The operation definitely cannot be performed at this time.
+    TemporarilyUnavailable,
+    /// The request was malformed.
+    MalformedRequest,
+    /// The node hit a general, indefinite error.
+    Crash,
+    /// The operation definitely failed and had no effects.
+    Abort,
+    /// The key does not exist.
+    KeyDoesNotExist,
+    /// The key already exists and the operation must not overwrite it.
+    KeyAlreadyExists,
+    /// A precondition (like the expected value of a CAS) was not met.
+    PreconditionFailed,
+    /// The transaction was aborted because of a conflict with another transaction.
+    TxnConflict,
+    /// Any other code, e.g. the custom ones from 1000 and above.
+    Custom(u16),
+}
+
+impl ErrorCode {
+    /// The numeric code that goes on the wire.
+    pub fn code(self) -> u16 {
+        match self {
+            ErrorCode::Timeout => 0,
+            ErrorCode::NodeNotFound => 1,
+            ErrorCode::NotSupported => 10,
+            ErrorCode::TemporarilyUnavailable => 11,
+            ErrorCode::MalformedRequest => 12,
+            ErrorCode::Crash => 13,
+            ErrorCode::Abort => 14,
+            ErrorCode::KeyDoesNotExist => 20,
+            ErrorCode::KeyAlreadyExists => 21,
+            ErrorCode::PreconditionFailed => 22,
+            ErrorCode::TxnConflict => 30,
+            ErrorCode::Custom(code) => code,
+        }
+    }
+
+    /// Whether an operation that failed with this error definitely did not take place.
+    ///
+    /// Indefinite errors (timeouts, crashes and unknown custom codes) leave it open
+    /// whether the operation had an effect or not.
+    pub fn is_definite(self) -> bool {
+        !matches!(
+            self,
+            ErrorCode::Timeout | ErrorCode::Crash | ErrorCode::Custom(_)
+        )
+    }
+}
+
+impl From<u16> for ErrorCode {
+    fn from(code: u16) -> Self {
+        match code {
+            0 => ErrorCode::Timeout,
+            1 => ErrorCode::NodeNotFound,
+            10 => ErrorCode::NotSupported,
+            11 => ErrorCode::TemporarilyUnavailable,
+            12 => ErrorCode::MalformedRequest,
+            13 => ErrorCode::Crash,
+            14 => ErrorCode::Abort,
+            20 => ErrorCode::KeyDoesNotExist,
+            21 => ErrorCode::KeyAlreadyExists,
+            22 => ErrorCode::PreconditionFailed,
+            30 => ErrorCode::TxnConflict,
+            code => ErrorCode::Custom(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for u16 {
+    fn from(code: ErrorCode) -> Self {
+        code.code()
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::Timeout => write!(f, "timeout"),
+            ErrorCode::NodeNotFound => write!(f, "node-not-found"),
+            ErrorCode::NotSupported => write!(f, "not-supported"),
+            ErrorCode::TemporarilyUnavailable => write!(f, "temporarily-unavailable"),
+            ErrorCode::MalformedRequest => write!(f, "malformed-request"),
+            ErrorCode::Crash => write!(f, "crash"),
+            ErrorCode::Abort => write!(f, "abort"),
+            ErrorCode::KeyDoesNotExist => write!(f, "key-does-not-exist"),
+            ErrorCode::KeyAlreadyExists => write!(f, "key-already-exists"),
+            ErrorCode::PreconditionFailed => write!(f, "precondition-failed"),
+            ErrorCode::TxnConflict => write!(f, "txn-conflict"),
+            ErrorCode::Custom(code) => write!(f, "custom error {code}"),
+        }
+    }
+}
+
+/// A Maelstrom error, either received from another node or to be sent as a reply.
+///
+/// This doubles as the body of `error` messages.
+/// Handlers can return it (e.g. with `?` or `bail!`) and the runtime will answer
+/// the request with an `error` message carrying the same code and text.
+/// Any other error returned by a handler is reported as a [`ErrorCode::Crash`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename = "error")]
+pub struct Error {
+    pub code: ErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+}
+
+impl Error {
+    pub fn new(code: ErrorCode, text: impl Into<String>) -> Self {
+        Self {
+            code,
+            text: Some(text.into()),
+        }
+    }
+
+    /// Whether the failed operation definitely did not take place.
+    pub fn is_definite(&self) -> bool {
+        self.code.is_definite()
+    }
+}
+
+impl From<ErrorCode> for Error {
+    fn from(code: ErrorCode) -> Self {
+        Self { code, text: None }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.text {
+            Some(text) => write!(f, "{}: {}", self.code, text),
+            None => write!(f, "{}", self.code),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
@@ -0,0 +1,190 @@
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{Error, ErrorCode, Node, RetryPolicy};
+
+/// The messages understood by the key-value services.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum KvBody {
+    Read {
+        key: Value,
+    },
+    ReadOk {
+        value: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    WriteOk,
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+    CasOk,
+}
+
+/// The key-value services that Maelstrom provides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum KvService {
+    /// A sequentially consistent store.
+    Seq,
+    /// A linearizable store.
+    Lin,
+    /// A last-write-wins store.
+    Lww,
+}
+
+impl KvService {
+    /// The node ID under which the service is reachable.
+    pub fn name(self) -> &'static str {
+        match self {
+            KvService::Seq => "seq-kv",
+            KvService::Lin => "lin-kv",
+            KvService::Lww => "lww-kv",
+        }
+    }
+}
+
+/// A typed client for one of Maelstrom's key-value services.
+///
+/// Keys and values can be anything that (de)serializes to JSON.
+/// Failed operations are reported as [`Error`]s, most notably [`ErrorCode::KeyDoesNotExist`]
+/// for reads of missing keys and [`ErrorCode::PreconditionFailed`] for failed CAS operations.
+///
+/// Reads and writes are retried according to the [`RetryPolicy`], but CAS operations are only
+/// sent once: if the first one went through and its reply got lost, a second one would fail
+/// as if someone else had changed the value. Instead they fail with an [`ErrorCode::Timeout`],
+/// and callers that can check whether their change took effect can try again.
+#[derive(Debug, Clone)]
+pub struct KvClient {
+    service: KvService,
+    policy: RetryPolicy,
+}
+
+impl KvClient {
+    pub fn new(service: KvService) -> Self {
+        Self {
+            service,
+            policy: RetryPolicy::default(),
+        }
+    }
+
+    /// Use `policy` instead of the default [`RetryPolicy`] for all requests.
+    pub fn with_retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn service(&self) -> KvService {
+        self.service
+    }
+
+    /// Read the value of `key`.
+    pub async fn read<T: DeserializeOwned>(
+        &self,
+        node: &Node,
+        key: impl Into<Value>,
+    ) -> Result<T, Error> {
+        let reply = self.request(node, KvBody::Read { key: key.into() }).await?;
+        match reply {
+            KvBody::ReadOk { value } => from_value(value),
+            other => Err(unexpected_reply(&other)),
+        }
+    }
+
+    /// Unconditionally set `key` to `value`.
+    pub async fn write<T: Serialize>(
+        &self,
+        node: &Node,
+        key: impl Into<Value>,
+        value: &T,
+    ) -> Result<(), Error> {
+        let request = KvBody::Write {
+            key: key.into(),
+            value: to_value(value)?,
+        };
+        match self.request(node, request).await? {
+            KvBody::WriteOk => Ok(()),
+            other => Err(unexpected_reply(&other)),
+        }
+    }
+
+    /// Set `key` to `to`, but only if its current value is `from`.
+    ///
+    /// Fails with an [`ErrorCode::Timeout`] if there's no reply to the single attempt,
+    /// in which case the value might have been set or not.
+    pub async fn cas<T: Serialize>(
+        &self,
+        node: &Node,
+        key: impl Into<Value>,
+        from: &T,
+        to: &T,
+    ) -> Result<(), Error> {
+        self.cas_inner(node, key.into(), from, to, false).await
+    }
+
+    /// Like [`KvClient::cas`], but if `key` doesn't exist yet it is created with the value `to`.
+    pub async fn cas_or_create<T: Serialize>(
+        &self,
+        node: &Node,
+        key: impl Into<Value>,
+        from: &T,
+        to: &T,
+    ) -> Result<(), Error> {
+        self.cas_inner(node, key.into(), from, to, true).await
+    }
+
+    async fn cas_inner<T: Serialize>(
+        &self,
+        node: &Node,
+        key: Value,
+        from: &T,
+        to: &T,
+        create_if_not_exists: bool,
+    ) -> Result<(), Error> {
+        let request = KvBody::Cas {
+            key,
+            from: to_value(from)?,
+            to: to_value(to)?,
+            create_if_not_exists,
+        };
+        let policy = RetryPolicy {
+            max_attempts: Some(1),
+            ..self.policy.clone()
+        };
+        let reply = node.rpc_with(self.service.name(), request, &policy).await?;
+        match reply.body.inner {
+            KvBody::CasOk => Ok(()),
+            other => Err(unexpected_reply(&other)),
+        }
+    }
+
+    async fn request(&self, node: &Node, request: KvBody) -> Result<KvBody, Error> {
+        let reply = node
+            .rpc_with(self.service.name(), request, &self.policy)
+            .await?;
+        Ok(reply.body.inner)
+    }
+}
+
+fn to_value<T: Serialize>(value: &T) -> Result<Value, Error> {
+    serde_json::to_value(value).map_err(|e| Error::new(ErrorCode::MalformedRequest, e.to_string()))
+}
+
+fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, Error> {
+    serde_json::from_value(value)
+        .map_err(|e| Error::new(ErrorCode::MalformedRequest, e.to_string()))
+}
+
+fn unexpected_reply(reply: &KvBody) -> Error {
+    Error::new(
+        ErrorCode::MalformedRequest,
+        format!("unexpected reply from KV store: {reply:?}"),
+    )
+}
@@ -1,183 +1,351 @@
-use std::{collections::HashMap, rc::Rc, time::Duration};
+use std::{
+    cell::{OnceCell, RefCell},
+    collections::HashMap,
+    rc::Rc,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Result};
 use futures::SinkExt;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::Value;
+#[cfg(feature = "sim")]
+use tokio::sync::mpsc::UnboundedSender;
 use tokio::{
     io,
-    sync::{
-        oneshot::{self, Sender},
-        Mutex,
-    },
-    task::{self, JoinHandle},
-    time::{self, Instant, MissedTickBehavior},
+    sync::{oneshot::Sender, Mutex},
+    task::{JoinSet, LocalSet},
 };
-use tokio_util::codec::{FramedWrite, LinesCodec};
+use tokio_stream::{Stream, StreamExt};
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
 
-// TODO: maybe we should implement some convenience functions,
-// like, e.g., `reply` to handle swapping src and dst etc.
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct Message {
+pub mod checker;
+mod error;
+mod kv;
+pub mod mvcc;
+mod rng;
+mod rpc;
+#[cfg(feature = "sim")]
+pub mod sim;
+pub mod workloads;
+
+pub use error::{Error, ErrorCode};
+pub use kv::{KvBody, KvClient, KvService};
+pub use rpc::RetryPolicy;
+
+use rng::Rng;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Message<B> {
     pub src: String,
     #[serde(rename = "dest")]
     pub dst: String,
-    pub body: MessageBody,
+    pub body: MessageBody<B>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct MessageBody {
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MessageBody<B> {
     #[serde(rename = "msg_id")]
     pub id: Option<u64>,
     pub in_reply_to: Option<u64>,
+    /// The part of the message that is specific to each type of message.
+    /// Each workload defines its own set of message types, see [`workloads`].
     #[serde(flatten)]
-    pub inner: InnerMessageBody,
+    pub inner: B,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(untagged)]
-pub enum ReadOkVariants {
-    Array { messages: Vec<u64> },
-    Single { value: u64 },
-    Kv { value: String },
-}
-
-/// The part of the message that is specific to each
-/// type of message.
+/// The messages of the initialization handshake, which is handled by the [`Runtime`].
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-pub enum InnerMessageBody {
-    // Common message types
+pub enum InitBody {
     Init {
         node_id: String,
         node_ids: Vec<String>,
     },
     InitOk,
-    Error {
-        // TODO: implement the Maelstrom error codes?
-        // See: https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors
-        code: u16,
-        text: Option<String>,
-    },
-    // 1. Echo challenge
-    Echo {
-        echo: String,
-    },
-    EchoOk {
-        echo: String,
-    },
-    // 2. Unique ID generation challenge
-    Generate,
-    GenerateOk {
-        id: String,
-    },
-    // 3. Broadcast challenge
-    Broadcast {
-        message: u64,
-    },
-    BroadcastOk,
-    // This message type is also reused in challenge 4
-    Read,
-    ReadOk(ReadOkVariants),
-    Topology {
-        topology: HashMap<String, Vec<String>>,
-    },
-    TopologyOk,
-    // 3e. Custom message for efficient broadcast
-    BatchBroadcast {
-        messages: Vec<u64>,
-    },
-    // 4. Grow-Only Counter challenge
-    Add {
-        delta: u64,
-    },
-    AddOk,
-    /// A read from the KV store
-    #[serde(rename = "read")]
-    ReadKv {
-        key: String,
-    },
-    /// A write to the KV store
-    #[serde(rename = "write")]
-    WriteKv {
-        key: String,
-        value: String,
-    },
-    /// A response to a Write request to the KV store
-    #[serde(rename = "write_ok")]
-    WriteKvOk,
-    /// A CAS operation on the KV store
-    #[serde(rename = "cas")]
-    CasKv {
-        key: String,
-        from: String,
-        to: String,
-        create_if_not_exists: bool,
-    },
-    /// A response to a CAS operation on the KV store
-    #[serde(rename = "cas_ok")]
-    CasKvOk,
-    // 5. Kafka-Style Log challenge
-    Send {
-        key: String,
-        msg: u64,
-    },
-    SendOk {
-        offset: u64,
-    },
-    Poll {
-        offsets: HashMap<String, u64>,
-    },
-    PollOk {
-        msgs: HashMap<String, Vec<(u64, u64)>>,
-    },
-    CommitOffsets {
-        offsets: HashMap<String, u64>,
-    },
-    CommitOffsetsOk,
-    ListCommittedOffsets {
-        keys: Vec<String>,
-    },
-    ListCommittedOffsetsOk {
-        offsets: HashMap<String, u64>,
-    },
 }
 
-impl Message {
+impl<B> Message<B> {
+    /// Build a reply to this message: source and destination are swapped,
+    /// and `in_reply_to` is set to the ID of this message.
+    pub fn reply<R>(&self, id: u64, inner: R) -> Message<R> {
+        Message {
+            src: self.dst.clone(),
+            dst: self.src.clone(),
+            body: MessageBody {
+                id: Some(id),
+                in_reply_to: self.body.id,
+                inner,
+            },
+        }
+    }
+
+    /// Split the message into its envelope and the type specific part of the body.
+    pub fn into_parts(self) -> (Message<()>, B) {
+        let envelope = Message {
+            src: self.src,
+            dst: self.dst,
+            body: MessageBody {
+                id: self.body.id,
+                in_reply_to: self.body.in_reply_to,
+                inner: (),
+            },
+        };
+        (envelope, self.body.inner)
+    }
+
+    /// Replace the body of this message, keeping everything else.
+    pub fn with_body<C>(self, inner: C) -> Message<C> {
+        Message {
+            src: self.src,
+            dst: self.dst,
+            body: MessageBody {
+                id: self.body.id,
+                in_reply_to: self.body.in_reply_to,
+                inner,
+            },
+        }
+    }
+}
+
+/// The `type` of a message body that hasn't been decoded yet.
+fn message_type(body: &Value) -> Option<&str> {
+    body.get("type").and_then(Value::as_str)
+}
+
+/// Where a node sends its messages.
+enum Output {
+    /// Newline delimited JSON on stdout, as Maelstrom expects.
+    Stdout(Mutex<FramedWrite<io::Stdout, LinesCodec>>),
+    /// The in-memory network of the [`sim`]ulator.
+    #[cfg(feature = "sim")]
+    Channel(UnboundedSender<Message<Value>>),
+}
+
+/// The runtime state of a node that is shared by all workloads:
+/// its identity, the message ID counter, the callbacks waiting for replies,
+/// and the output stream.
+pub struct Node {
+    id: OnceCell<String>,
+    node_ids: OnceCell<Vec<String>>,
+    msg_id: AtomicU64,
+    callbacks: RefCell<HashMap<u64, Sender<Message<Value>>>>,
+    output: Output,
+    rng: RefCell<Rng>,
+}
+
+impl Node {
+    fn new(output: Output, rng: Rng) -> Self {
+        Self {
+            id: OnceCell::new(),
+            node_ids: OnceCell::new(),
+            msg_id: AtomicU64::new(1),
+            callbacks: RefCell::new(HashMap::new()),
+            output,
+            rng: RefCell::new(rng),
+        }
+    }
+
+    /// The ID of this node, as assigned by the `Init` message.
+    ///
+    /// # Panics
+    /// Panics if the node has not received its `Init` message yet.
+    pub fn id(&self) -> &str {
+        self.id.get().expect("node has not been initialized yet")
+    }
+
+    /// The IDs of all nodes in the cluster (including this one), as sent in the `Init` message.
+    ///
+    /// # Panics
+    /// Panics if the node has not received its `Init` message yet.
+    pub fn node_ids(&self) -> &[String] {
+        self.node_ids
+            .get()
+            .expect("node has not been initialized yet")
+    }
+
+    /// Allocate a fresh message ID.
+    pub fn next_msg_id(&self) -> u64 {
+        self.msg_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Build a reply to `msg` with a fresh message ID.
+    pub fn reply<B, R>(&self, msg: &Message<B>, inner: R) -> Message<R> {
+        msg.reply(self.next_msg_id(), inner)
+    }
+
+    /// Build an error reply to `msg` with a fresh message ID.
+    pub fn error_reply<B>(&self, msg: &Message<B>, error: Error) -> Message<Error> {
+        self.reply(msg, error)
+    }
+
+    /// Build a new request from this node to `dst` with a fresh message ID.
+    pub fn request<B>(&self, dst: impl Into<String>, inner: B) -> Message<B> {
+        Message {
+            src: self.id().to_owned(),
+            dst: dst.into(),
+            body: MessageBody {
+                id: Some(self.next_msg_id()),
+                in_reply_to: None,
+                inner,
+            },
+        }
+    }
+
     /// Serialize and send the message in a newline delimited way, as the Maelstrom protocol expects.
-    pub async fn send(&self, output: Rc<Mutex<FramedWrite<io::Stdout, LinesCodec>>>) -> Result<()> {
-        let msg = serde_json::to_string(self)?;
-        output.lock().await.send(msg).await?;
+    pub async fn send<B: Serialize>(&self, msg: &Message<B>) -> Result<()> {
+        match &self.output {
+            Output::Stdout(output) => {
+                let msg = serde_json::to_string(msg)?;
+                output.lock().await.send(msg).await?;
+            }
+            #[cfg(feature = "sim")]
+            Output::Channel(output) => {
+                let msg = serde_json::from_value(serde_json::to_value(msg)?)?;
+                if output.send(msg).is_err() {
+                    bail!("The network has shut down");
+                }
+            }
+        }
         Ok(())
     }
 
-    /// Send a message and retry until it is acknowledged by the receiver.
-    pub async fn send_with_retry(
+    /// Hand a reply over to the task waiting for it, if there is one.
+    fn dispatch_reply(&self, in_reply_to: u64, msg: Message<Value>) {
+        if let Some(tx) = self.callbacks.borrow_mut().remove(&in_reply_to) {
+            let _ = tx.send(msg);
+        }
+    }
+}
+
+/// The workload specific part of a node.
+///
+/// The runtime calls [`Handler::init`] once the node has received its `Init` message,
+/// and [`Handler::handle`] for every other message that is not a reply to one of our own requests.
+#[allow(async_fn_in_trait)]
+pub trait Handler: 'static {
+    /// The message types of this workload.
+    type Body: DeserializeOwned;
+
+    /// Runs once the node has received its `Init` message, but before the `InitOk` is sent.
+    async fn init(self: Rc<Self>, node: Rc<Node>) -> Result<()> {
+        let _ = node;
+        Ok(())
+    }
+
+    /// Handle a single request.
+    ///
+    /// If this returns an [`Error`], the runtime answers the request with that error.
+    /// Requests with a type that is not part of [`Handler::Body`] never make it here,
+    /// the runtime answers them with [`ErrorCode::NotSupported`].
+    async fn handle(self: Rc<Self>, node: Rc<Node>, msg: Message<Self::Body>) -> Result<()>;
+}
+
+/// Drives a node: reads messages from stdin, handles the `Init` handshake,
+/// routes replies to their callbacks, and dispatches everything else to the workload's [`Handler`].
+pub struct Runtime<H> {
+    handler: Rc<H>,
+}
+
+impl<H: Handler> Runtime<H> {
+    pub fn new(handler: H) -> Self {
+        Self {
+            handler: Rc::new(handler),
+        }
+    }
+
+    /// Run the node until stdin is closed and all in-flight messages have been handled.
+    ///
+    /// Every message that is not a reply to one of our own requests is handled in its own task.
+    pub async fn run(self) -> Result<()> {
+        let codec = LinesCodec::new();
+        let input = FramedRead::new(io::stdin(), codec.clone())
+            .map(|line| Ok(serde_json::from_str(&line?)?));
+        let output = Output::Stdout(Mutex::new(FramedWrite::new(io::stdout(), codec)));
+        let node = Rc::new(Node::new(output, Rng::from_entropy()));
+        LocalSet::new().run_until(self.serve(node, input)).await
+    }
+
+    /// Handle the messages from `input` until it ends and all in-flight messages have been handled.
+    ///
+    /// Must be run within a [`LocalSet`].
+    async fn serve(
         self,
-        callbacks: &Mutex<HashMap<u64, Sender<Message>>>,
-        output: Rc<Mutex<FramedWrite<io::Stdout, LinesCodec>>>,
-    ) -> Result<JoinHandle<Result<Self>>> {
-        let (tx, mut rx) = oneshot::channel();
-        callbacks.lock().await.insert(self.body.id.unwrap(), tx);
-        let task = async move {
-            self.send(output.clone()).await?;
-            let start = Instant::now() + Duration::from_millis(1000);
-            let mut interval = time::interval_at(start, Duration::from_millis(1000));
-            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
-            loop {
-                tokio::select! {
-                    biased;
-                    m = &mut rx => {
-                        debug_assert!(m.is_ok());
-                        return m.map_err(|e| anyhow!(e));
+        node: Rc<Node>,
+        input: impl Stream<Item = Result<Message<Value>>>,
+    ) -> Result<()> {
+        let handler = self.handler;
+        let mut input = std::pin::pin!(input);
+        let mut tasks = JoinSet::new();
+        while let Some(message) = input.try_next().await? {
+            // Reap the tasks that have finished in the meantime.
+            while tasks.try_join_next().is_some() {}
+            if let Some(id) = message.body.in_reply_to {
+                node.dispatch_reply(id, message);
+                continue;
+            }
+            // NOTE: I'm assuming that all messages we receive are actually intended for us
+            // and thus we don't need to check the destination value matches our id.
+            if message_type(&message.body.inner) == Some("init") {
+                let InitBody::Init { node_id, node_ids } =
+                    serde_json::from_value(message.body.inner.clone())?
+                else {
+                    bail!("Received malformed Init message");
+                };
+                if node.id.set(node_id).is_err() {
+                    bail!("Received Init message, but we already have a node ID");
+                }
+                let _ = node.node_ids.set(node_ids);
+                let node = node.clone();
+                let handler = handler.clone();
+                tasks.spawn_local(async move {
+                    if let Err(e) = handler.init(node.clone()).await {
+                        eprintln!("Failed to initialize node: {e:?}");
+                        return;
                     }
-                    _ = interval.tick() => {
-                        self.send(output.clone()).await?;
+                    let reply = node.reply(&message, InitBody::InitOk);
+                    if let Err(e) = node.send(&reply).await {
+                        eprintln!("Failed to send InitOk: {e:?}");
                     }
-                };
+                });
+                continue;
             }
-        };
-        let jh = task::spawn_local(task);
-        Ok(jh)
+            let node = node.clone();
+            let handler = handler.clone();
+            tasks.spawn_local(async move {
+                // Keep the envelope around, so we can still answer with an error
+                // after the handler has consumed the message.
+                let (request, inner) = message.into_parts();
+                let result = match serde_json::from_value(inner) {
+                    Ok(inner) => {
+                        handler
+                            .handle(node.clone(), request.clone().with_body(inner))
+                            .await
+                    }
+                    // Serde doesn't tell us directly whether it didn't know the type
+                    // or whether the fields didn't match, so we have to look at the message.
+                    Err(e) if e.to_string().starts_with("unknown variant") => {
+                        Err(Error::new(ErrorCode::NotSupported, e.to_string()).into())
+                    }
+                    Err(e) => Err(Error::new(ErrorCode::MalformedRequest, e.to_string()).into()),
+                };
+                if let Err(e) = result {
+                    let error = match e.downcast::<Error>() {
+                        Ok(error) => error,
+                        Err(e) => {
+                            eprintln!("Failed to handle message: {e:?}");
+                            Error::new(ErrorCode::Crash, e.to_string())
+                        }
+                    };
+                    let reply = node.error_reply(&request, error);
+                    if let Err(e) = node.send(&reply).await {
+                        eprintln!("Failed to send error reply: {e:?}");
+                    }
+                }
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+        Ok(())
     }
 }
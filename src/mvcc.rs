@@ -0,0 +1,109 @@
+//! A multi-version store for the state of transactional workloads, see [`Mvcc`].
+
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// A key-value store that keeps several versions of every value, each with the timestamp of the
+/// transaction that wrote it (multi-version concurrency control).
+///
+/// Transactions read a snapshot as of their start timestamp, which no later commit changes,
+/// and only conflict with each other if they write the same key: the first one to commit wins.
+/// Together that's snapshot isolation. Versions that no snapshot can read anymore are dropped
+/// by [`Mvcc::gc`].
+///
+/// Timestamps can be anything ordered, e.g. Lamport clocks with a node ID to break ties.
+/// Versions can be added in any order, so replicas that receive the same writes in different
+/// orders end up with the same versions.
+#[derive(Debug, Clone)]
+pub struct Mvcc<K, V, T> {
+    /// The versions of every key, oldest first.
+    chains: HashMap<K, Vec<(T, V)>>,
+}
+
+impl<K, V, T> Default for Mvcc<K, V, T> {
+    fn default() -> Self {
+        Self {
+            chains: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V, T: Ord + Clone> Mvcc<K, V, T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The value of `key` in the snapshot as of `at`, i.e. its latest version at or before `at`.
+    pub fn read(&self, key: &K, at: &T) -> Option<&V> {
+        let chain = self.chains.get(key)?;
+        let visible = chain.partition_point(|(ts, _)| ts <= at);
+        chain[..visible].last().map(|(_, value)| value)
+    }
+
+    /// The values of all keys in the snapshot as of `at`, in no particular order.
+    pub fn snapshot<'a>(&'a self, at: &'a T) -> impl Iterator<Item = (&'a K, &'a V)> + 'a {
+        self.chains
+            .keys()
+            .filter_map(move |key| Some((key, self.read(key, at)?)))
+    }
+
+    /// The latest version of `key`, with its timestamp.
+    pub fn latest(&self, key: &K) -> Option<(&T, &V)> {
+        let (ts, value) = self.chains.get(key)?.last()?;
+        Some((ts, value))
+    }
+
+    /// Add a version of `key` that was written at `ts`.
+    /// A version with the same timestamp is replaced, so writes can safely be applied twice.
+    pub fn write(&mut self, key: K, ts: T, value: V) {
+        let chain = self.chains.entry(key).or_default();
+        let i = chain.partition_point(|(written, _)| *written < ts);
+        match chain.get_mut(i) {
+            Some((written, old)) if *written == ts => *old = value,
+            _ => chain.insert(i, (ts, value)),
+        }
+    }
+
+    /// The first of `keys` that has a version after `start`, which a transaction that reads
+    /// the snapshot as of `start` can't write without overwriting a concurrent transaction.
+    pub fn conflict<'a>(&self, keys: impl IntoIterator<Item = &'a K>, start: &T) -> Option<&'a K>
+    where
+        K: 'a,
+    {
+        keys.into_iter()
+            .find(|key| self.latest(key).is_some_and(|(ts, _)| ts > start))
+    }
+
+    /// Add the `writes` of a transaction that read the snapshot as of `start` and commits at `ts`,
+    /// unless another transaction wrote one of the keys after `start`. Returns that key if so,
+    /// in which case none of the writes are added.
+    pub fn commit(
+        &mut self,
+        start: &T,
+        ts: T,
+        writes: impl IntoIterator<Item = (K, V)>,
+    ) -> Result<(), K> {
+        let writes: Vec<(K, V)> = writes.into_iter().collect();
+        if let Some(key) = self.conflict(writes.iter().map(|(key, _)| key), start) {
+            return Err(key.clone());
+        }
+        for (key, value) in writes {
+            self.write(key, ts.clone(), value);
+        }
+        Ok(())
+    }
+
+    /// Drop the versions that no snapshot as of `horizon` or later can read:
+    /// of every key, all those before its latest version at or before `horizon`.
+    pub fn gc(&mut self, horizon: &T) {
+        for chain in self.chains.values_mut() {
+            let visible = chain.partition_point(|(ts, _)| ts <= horizon);
+            chain.drain(..visible.saturating_sub(1));
+        }
+    }
+
+    /// The number of versions of all keys together.
+    pub fn versions(&self) -> usize {
+        self.chains.values().map(Vec::len).sum()
+    }
+}
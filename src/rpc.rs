@@ -0,0 +1,147 @@
+use std::time::Duration;
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::{
+    sync::oneshot,
+    time::{self, Instant},
+};
+
+use crate::{message_type, Error, ErrorCode, Message, Node};
+
+/// Controls how [`Node::rpc_with`] retries a request that has not been answered yet.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// How long to wait for a reply to the first attempt.
+    pub initial_backoff: Duration,
+    /// Upper bound for the time between two attempts.
+    pub max_backoff: Duration,
+    /// Factor by which the backoff grows after every attempt.
+    pub multiplier: f64,
+    /// Fraction of the backoff that is randomized, so that the retries of many
+    /// requests that were sent at the same time don't all line up.
+    /// Clamped to between 0 and 1, so the backoff never goes negative.
+    pub jitter: f64,
+    /// Give up after this many attempts. `None` retries until the deadline.
+    pub max_attempts: Option<u32>,
+    /// Give up if there is no reply after this long. `None` waits forever.
+    pub timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    /// Retry forever, starting at 500 ms between attempts and backing off up to 2 s.
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_millis(2000),
+            multiplier: 2.0,
+            jitter: 0.1,
+            max_attempts: None,
+            timeout: None,
+        }
+    }
+}
+
+/// Removes the callback of a request once we stop waiting for the reply,
+/// no matter if we got one, gave up, or the waiting task was cancelled.
+struct Pending<'a> {
+    node: &'a Node,
+    id: u64,
+}
+
+impl Drop for Pending<'_> {
+    fn drop(&mut self) {
+        self.node.callbacks.borrow_mut().remove(&self.id);
+    }
+}
+
+impl Node {
+    /// Send a request to `dst` and wait for the reply, retrying with the default [`RetryPolicy`].
+    ///
+    /// Error replies are returned as [`Error`]s.
+    pub async fn rpc<Req, Resp>(
+        &self,
+        dst: impl Into<String>,
+        inner: Req,
+    ) -> Result<Message<Resp>, Error>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        self.rpc_with(dst, inner, &RetryPolicy::default()).await
+    }
+
+    /// Send a request to `dst` and wait for the reply, retrying according to `policy`.
+    ///
+    /// Gives up with an [`ErrorCode::Timeout`] once the policy is exhausted.
+    /// Error replies are returned as [`Error`]s.
+    pub async fn rpc_with<Req, Resp>(
+        &self,
+        dst: impl Into<String>,
+        inner: Req,
+        policy: &RetryPolicy,
+    ) -> Result<Message<Resp>, Error>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let request = self.request(dst, inner);
+        let id = request.body.id.expect("requests always have an ID");
+        let (tx, mut rx) = oneshot::channel();
+        self.callbacks.borrow_mut().insert(id, tx);
+        let _pending = Pending { node: self, id };
+
+        let deadline = policy.timeout.map(|timeout| Instant::now() + timeout);
+        let mut backoff = policy.initial_backoff;
+        let mut attempts = 0;
+        loop {
+            self.send(&request)
+                .await
+                .map_err(|e| Error::new(ErrorCode::Crash, e.to_string()))?;
+            attempts += 1;
+
+            let mut wait = self.jittered(backoff, policy.jitter);
+            if let Some(deadline) = deadline {
+                wait = wait.min(deadline.saturating_duration_since(Instant::now()));
+            }
+            if let Ok(reply) = time::timeout(wait, &mut rx).await {
+                let reply = reply
+                    .map_err(|_| Error::new(ErrorCode::Crash, "reply callback was dropped"))?;
+                return decode_reply(reply);
+            }
+
+            let out_of_attempts = policy.max_attempts.is_some_and(|max| attempts >= max);
+            let past_deadline = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            if out_of_attempts || past_deadline {
+                return Err(Error::new(
+                    ErrorCode::Timeout,
+                    format!("no reply from {} after {attempts} attempts", request.dst),
+                ));
+            }
+            backoff = backoff.mul_f64(policy.multiplier).min(policy.max_backoff);
+        }
+    }
+
+    /// Randomly stretch or shrink `backoff` by up to the `jitter` fraction.
+    fn jittered(&self, backoff: Duration, jitter: f64) -> Duration {
+        // `Duration::mul_f64` panics on negative factors.
+        let jitter = jitter.clamp(0.0, 1.0);
+        let r = self.rng.borrow_mut().next_f64();
+        backoff.mul_f64(1.0 + jitter * (2.0 * r - 1.0))
+    }
+}
+
+/// Decode the body of a reply, turning `error` messages into [`Error`]s.
+fn decode_reply<Resp: DeserializeOwned>(reply: Message<Value>) -> Result<Message<Resp>, Error> {
+    let is_error = message_type(&reply.body.inner) == Some("error");
+    let (reply, inner) = reply.into_parts();
+    if is_error {
+        return Err(serde_json::from_value(inner).map_err(malformed_reply)?);
+    }
+    let inner = serde_json::from_value(inner).map_err(malformed_reply)?;
+    Ok(reply.with_body(inner))
+}
+
+fn malformed_reply(e: serde_json::Error) -> Error {
+    Error::new(ErrorCode::MalformedRequest, format!("malformed reply: {e}"))
+}
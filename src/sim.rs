@@ -0,0 +1,264 @@
+//! An in-process simulator, to test nodes without running Maelstrom.
+//!
+//! A [`Cluster`] runs a number of nodes with the same [`Handler`] in the current thread,
+//! connects them through an in-memory network with configurable latency, message drops
+//! and partitions, and provides in-memory versions of the seq-kv, lin-kv and lww-kv services.
+//! [`Client`]s play the part of Maelstrom's clients and send requests to the nodes.
+//!
+//! Simulations are deterministic: time is virtual, and all random decisions of the network
+//! and the nodes are derived from [`Config::seed`]. A failing run can be replayed by setting
+//! the `SIM_SEED` environment variable to the seed it printed, and [`Cluster::trace`]
+//! records every message so that runs can be compared.
+//! That only holds for handlers that do the same: a handler that sends messages in the
+//! iteration order of a `HashMap`, whose hasher is seeded anew in every run, has to sort first.
+//!
+//! ```no_run
+//! use dist_sys_challenge::{sim::{self, Cluster, Config}, workloads::echo::{Echo, EchoBody}};
+//!
+//! sim::run(async {
+//!     let cluster = Cluster::start(Config::default(), || Echo).await.unwrap();
+//!     let client = cluster.client();
+//!     let reply: EchoBody = client
+//!         .rpc("n0", EchoBody::Echo { echo: "hello".into() })
+//!         .await
+//!         .unwrap();
+//! });
+//! ```
+
+use std::{
+    cell::{Cell, RefCell},
+    env,
+    future::Future,
+    ops::Range,
+    rc::Rc,
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use tokio::{
+    runtime,
+    sync::mpsc::UnboundedSender,
+    task::{self, LocalSet},
+};
+use tokio_stream::{wrappers::UnboundedReceiverStream, StreamExt};
+
+use crate::{
+    Error, Handler, InitBody, KvService, Message, Node, Output, RetryPolicy, Rng, Runtime,
+};
+
+mod kv;
+mod network;
+
+use network::Network;
+
+/// Run `future` to completion on a fresh single-threaded runtime, as the simulator requires.
+///
+/// Time is virtual: it only advances when all tasks are waiting for a timer,
+/// and then jumps straight to the next one.
+pub fn run<F: Future>(future: F) -> F::Output {
+    let runtime = runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .expect("failed to build the tokio runtime");
+    LocalSet::new().block_on(&runtime, future)
+}
+
+/// The setup of a simulated cluster.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The number of nodes, which are named `n0`, `n1`, and so on.
+    pub node_count: usize,
+    /// Every message is delayed by a random duration from this range.
+    pub latency: Range<Duration>,
+    /// The probability with which a message is lost.
+    /// Only applies once the cluster has been initialized.
+    pub drop_rate: f64,
+    /// The seed from which all random decisions are derived.
+    pub seed: u64,
+}
+
+impl Default for Config {
+    /// A single node, with up to 5 ms of latency and no message drops.
+    ///
+    /// The seed is taken from the `SIM_SEED` environment variable, or chosen at random.
+    fn default() -> Self {
+        let seed = match env::var("SIM_SEED") {
+            Ok(seed) => seed.parse().expect("SIM_SEED must be a number"),
+            Err(_) => Rng::from_entropy().next_u64(),
+        };
+        Self {
+            node_count: 1,
+            latency: Duration::ZERO..Duration::from_millis(5),
+            drop_rate: 0.0,
+            seed,
+        }
+    }
+}
+
+/// Something that happened to a message on the simulated network.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    /// The virtual time since the cluster was started.
+    pub at: Duration,
+    pub kind: EventKind,
+    pub message: Message<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// The message is on its way.
+    Sent,
+    /// The message was lost, or its destination was unreachable.
+    Dropped,
+    /// The message arrived at its destination.
+    Delivered,
+}
+
+/// A running cluster of simulated nodes.
+///
+/// The nodes keep running in the background until the runtime shuts down.
+pub struct Cluster {
+    network: Rc<Network>,
+    output: UnboundedSender<Message<Value>>,
+    node_ids: Vec<String>,
+    next_client: Cell<usize>,
+    rng: RefCell<Rng>,
+}
+
+impl Cluster {
+    /// Start a cluster whose nodes are driven by handlers created with `handler`,
+    /// and wait until all nodes have been initialized.
+    ///
+    /// Must be called within a [`LocalSet`], e.g. inside of [`run`].
+    pub async fn start<H: Handler>(
+        config: Config,
+        mut handler: impl FnMut() -> H,
+    ) -> Result<Self, Error> {
+        // Print the seed, so that failed tests show it.
+        eprintln!("Simulating with seed {}", config.seed);
+        let mut rng = Rng::new(config.seed);
+        let network = Network::new(config.latency, Rng::new(rng.next_u64()));
+        let output = network.start();
+
+        for service in [KvService::Seq, KvService::Lin, KvService::Lww] {
+            let inbox = network.register(service.name());
+            task::spawn_local(kv::serve(inbox, output.clone()));
+        }
+
+        let node_ids: Vec<String> = (0..config.node_count).map(|i| format!("n{i}")).collect();
+        for id in &node_ids {
+            let input = UnboundedReceiverStream::new(network.register(id)).map(Ok);
+            let node = Node::new(Output::Channel(output.clone()), Rng::new(rng.next_u64()));
+            let node = Rc::new(node);
+            let runtime = Runtime::new(handler());
+            let id = id.clone();
+            task::spawn_local(async move {
+                if let Err(e) = runtime.serve(node, input).await {
+                    eprintln!("Node {id} failed: {e:?}");
+                }
+            });
+        }
+
+        let cluster = Self {
+            network,
+            output,
+            node_ids,
+            next_client: Cell::new(0),
+            rng: RefCell::new(rng),
+        };
+        let client = cluster.client();
+        for id in &cluster.node_ids {
+            let init = InitBody::Init {
+                node_id: id.clone(),
+                node_ids: cluster.node_ids.clone(),
+            };
+            client.rpc::<_, InitBody>(id, init).await?;
+        }
+        cluster.network.set_drop_rate(config.drop_rate);
+        Ok(cluster)
+    }
+
+    /// The IDs of all nodes in the cluster.
+    pub fn node_ids(&self) -> &[String] {
+        &self.node_ids
+    }
+
+    /// Connect a new client to the cluster. Clients are named `c0`, `c1`, and so on.
+    pub fn client(&self) -> Client {
+        let i = self.next_client.replace(self.next_client.get() + 1);
+        let id = format!("c{i}");
+        let mut inbox = self.network.register(&id);
+        let rng = Rng::new(self.rng.borrow_mut().next_u64());
+        let node = Rc::new(Node::new(Output::Channel(self.output.clone()), rng));
+        let _ = node.id.set(id);
+        let _ = node.node_ids.set(self.node_ids.clone());
+
+        // Clients only ever receive replies to their own requests.
+        let receiver = node.clone();
+        task::spawn_local(async move {
+            while let Some(msg) = inbox.recv().await {
+                if let Some(id) = msg.body.in_reply_to {
+                    receiver.dispatch_reply(id, msg);
+                }
+            }
+        });
+        Client { node }
+    }
+
+    /// Split the given nodes into components that can't reach each other,
+    /// replacing any previous partition. Clients and services can still reach every node.
+    pub fn partition(&self, components: &[&[&str]]) {
+        self.network.partition(components);
+    }
+
+    /// Remove all partitions.
+    pub fn heal(&self) {
+        self.network.heal();
+    }
+
+    /// Change the probability with which a message is lost.
+    pub fn set_drop_rate(&self, drop_rate: f64) {
+        self.network.set_drop_rate(drop_rate);
+    }
+
+    /// Everything that happened on the network so far, in order.
+    /// Two runs with the same seed and the same client operations produce the same trace.
+    pub fn trace(&self) -> Vec<Event> {
+        self.network.trace()
+    }
+}
+
+/// A client of a simulated cluster.
+pub struct Client {
+    node: Rc<Node>,
+}
+
+impl Client {
+    /// Like Maelstrom's clients we don't retry requests, but give up after a while.
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    pub fn id(&self) -> &str {
+        self.node.id()
+    }
+
+    /// Send a request to `dst` and wait for the reply.
+    ///
+    /// Fails with an [`ErrorCode::Timeout`](crate::ErrorCode::Timeout) if there is no reply
+    /// within a second, and with the error sent by the node if it answers with an error.
+    pub async fn rpc<Req, Resp>(&self, dst: impl Into<String>, inner: Req) -> Result<Resp, Error>
+    where
+        Req: Serialize,
+        Resp: DeserializeOwned,
+    {
+        let policy = RetryPolicy {
+            initial_backoff: Self::TIMEOUT,
+            jitter: 0.0,
+            max_attempts: Some(1),
+            ..RetryPolicy::default()
+        };
+        let reply = self.node.rpc_with(dst, inner, &policy).await?;
+        Ok(reply.body.inner)
+    }
+}
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+
+use serde_json::Value;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+
+use crate::{Error, ErrorCode, KvBody, Message};
+
+/// Answer the requests to an in-memory key-value service until the network shuts down.
+///
+/// Requests are applied one at a time in the order in which they arrive,
+/// so the store is linearizable. That is stronger than what seq-kv and lww-kv promise,
+/// so it's a valid stand-in for all three of Maelstrom's services.
+pub(crate) async fn serve(
+    mut inbox: UnboundedReceiver<Message<Value>>,
+    output: UnboundedSender<Message<Value>>,
+) {
+    // The keys can be any JSON value, so we index by their serialized form.
+    let mut store = HashMap::new();
+    let mut msg_id = 0;
+    while let Some(msg) = inbox.recv().await {
+        msg_id += 1;
+        let (request, inner) = msg.into_parts();
+        let result = serde_json::from_value(inner)
+            .map_err(|e| Error::new(ErrorCode::MalformedRequest, e.to_string()))
+            .and_then(|body| apply(&mut store, body));
+        let reply = match result {
+            Ok(body) => serde_json::to_value(body),
+            Err(error) => serde_json::to_value(error),
+        }
+        .expect("KV bodies always serialize");
+        if output.send(request.reply(msg_id, reply)).is_err() {
+            return;
+        }
+    }
+}
+
+fn apply(store: &mut HashMap<String, Value>, body: KvBody) -> Result<KvBody, Error> {
+    match body {
+        KvBody::Read { key } => match store.get(&key.to_string()) {
+            Some(value) => Ok(KvBody::ReadOk {
+                value: value.clone(),
+            }),
+            None => Err(key_does_not_exist(&key)),
+        },
+        KvBody::Write { key, value } => {
+            store.insert(key.to_string(), value);
+            Ok(KvBody::WriteOk)
+        }
+        KvBody::Cas {
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        } => match store.get_mut(&key.to_string()) {
+            Some(current) if *current == from => {
+                *current = to;
+                Ok(KvBody::CasOk)
+            }
+            Some(current) => Err(Error::new(
+                ErrorCode::PreconditionFailed,
+                format!("expected {from}, but had {current}"),
+            )),
+            None if create_if_not_exists => {
+                store.insert(key.to_string(), to);
+                Ok(KvBody::CasOk)
+            }
+            None => Err(key_does_not_exist(&key)),
+        },
+        other => Err(Error::new(
+            ErrorCode::NotSupported,
+            format!("KV services don't handle {other:?}"),
+        )),
+    }
+}
+
+fn key_does_not_exist(key: &Value) -> Error {
+    Error::new(
+        ErrorCode::KeyDoesNotExist,
+        format!("key {key} does not exist"),
+    )
+}
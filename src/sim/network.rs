@@ -0,0 +1,134 @@
+use std::{cell::RefCell, collections::HashMap, ops::Range, rc::Rc, time::Duration};
+
+use serde_json::Value;
+use tokio::{
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task,
+    time::{self, Instant},
+};
+
+use super::{Event, EventKind};
+use crate::{Message, Rng};
+
+/// An in-memory network that carries the messages between the nodes, clients
+/// and services of a simulated cluster.
+///
+/// Every message is delayed by a random latency, so messages can overtake each other,
+/// and it may be dropped at random or because its source and destination are partitioned.
+/// Every decision is made by a seeded [`Rng`], so the network behaves the same way in every
+/// run with the same seed.
+pub(crate) struct Network {
+    state: RefCell<State>,
+    rng: RefCell<Rng>,
+    start: Instant,
+    trace: RefCell<Vec<Event>>,
+}
+
+struct State {
+    latency: Range<Duration>,
+    drop_rate: f64,
+    /// The partition component of every node that is currently partitioned.
+    /// Endpoints that are not listed (clients and services) can talk to everyone.
+    components: HashMap<String, usize>,
+    inboxes: HashMap<String, UnboundedSender<Message<Value>>>,
+}
+
+impl State {
+    fn is_cut(&self, src: &str, dst: &str) -> bool {
+        match (self.components.get(src), self.components.get(dst)) {
+            (Some(a), Some(b)) => a != b,
+            _ => false,
+        }
+    }
+}
+
+impl Network {
+    pub(crate) fn new(latency: Range<Duration>, rng: Rng) -> Rc<Self> {
+        Rc::new(Self {
+            state: RefCell::new(State {
+                latency,
+                drop_rate: 0.0,
+                components: HashMap::new(),
+                inboxes: HashMap::new(),
+            }),
+            rng: RefCell::new(rng),
+            start: Instant::now(),
+            trace: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// Spawn the task that routes the messages, and return the channel to send them on.
+    pub(crate) fn start(self: &Rc<Self>) -> UnboundedSender<Message<Value>> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let network = self.clone();
+        task::spawn_local(async move {
+            while let Some(msg) = rx.recv().await {
+                network.route(msg);
+            }
+        });
+        tx
+    }
+
+    /// Make `id` reachable, and return the inbox in which its messages will arrive.
+    pub(crate) fn register(&self, id: &str) -> UnboundedReceiver<Message<Value>> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.state.borrow_mut().inboxes.insert(id.to_owned(), tx);
+        rx
+    }
+
+    pub(crate) fn set_drop_rate(&self, drop_rate: f64) {
+        self.state.borrow_mut().drop_rate = drop_rate;
+    }
+
+    /// Split the given nodes into components that can't reach each other.
+    pub(crate) fn partition(&self, components: &[&[&str]]) {
+        let mut state = self.state.borrow_mut();
+        state.components.clear();
+        for (i, component) in components.iter().enumerate() {
+            for &id in component.iter() {
+                state.components.insert(id.to_owned(), i);
+            }
+        }
+    }
+
+    pub(crate) fn heal(&self) {
+        self.state.borrow_mut().components.clear();
+    }
+
+    /// Everything that happened on the network so far.
+    pub(crate) fn trace(&self) -> Vec<Event> {
+        self.trace.borrow().clone()
+    }
+
+    fn record(&self, kind: EventKind, message: &Message<Value>) {
+        self.trace.borrow_mut().push(Event {
+            at: self.start.elapsed(),
+            kind,
+            message: message.clone(),
+        });
+    }
+
+    fn route(self: &Rc<Self>, msg: Message<Value>) {
+        let state = self.state.borrow();
+        let mut rng = self.rng.borrow_mut();
+        // Always draw, so that partitions don't shift the random numbers of later messages.
+        let dropped = rng.next_f64() < state.drop_rate;
+        let inbox = match state.inboxes.get(&msg.dst) {
+            Some(inbox) if !dropped && !state.is_cut(&msg.src, &msg.dst) => inbox.clone(),
+            // Like Maelstrom, we silently drop messages to nodes that don't exist.
+            _ => {
+                self.record(EventKind::Dropped, &msg);
+                return;
+            }
+        };
+        self.record(EventKind::Sent, &msg);
+        let spread = state.latency.end.saturating_sub(state.latency.start);
+        let latency = state.latency.start + spread.mul_f64(rng.next_f64());
+        let network = self.clone();
+        task::spawn_local(async move {
+            time::sleep(latency).await;
+            network.record(EventKind::Delivered, &msg);
+            let _ = inbox.send(msg);
+        });
+    }
+}
@@ -0,0 +1,14 @@
+//! The message types and [`Handler`](crate::Handler)s of the individual workloads.
+//!
+//! The binaries only wire a handler up to a [`Runtime`](crate::Runtime),
+//! so that the same handlers can also be run in the [`sim`](crate::sim)ulator.
+//!
+//! Each workload only accepts its own messages, so that there is no ambiguity
+//! between e.g. the `read` of the broadcast workload and the `read` of the KV store.
+
+pub mod broadcast;
+pub mod echo;
+pub mod g_counter;
+pub mod kafka;
+pub mod txn_rw_register;
+pub mod unique_ids;
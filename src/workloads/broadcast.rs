@@ -0,0 +1,222 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::rc::Rc;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::task;
+use tokio::time::{self, Duration, Instant, MissedTickBehavior};
+
+use crate::{Error, ErrorCode, Handler, Message, Node};
+
+/// The maximum number of children per node in the broadcast tree.
+const FANOUT: usize = 4;
+
+/// 3. Broadcast challenge
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum BroadcastBody {
+    Broadcast {
+        message: u64,
+    },
+    BroadcastOk,
+    Read,
+    ReadOk {
+        messages: Vec<u64>,
+    },
+    Topology {
+        topology: HashMap<String, Vec<String>>,
+    },
+    TopologyOk,
+    // 3e. Custom message for efficient broadcast
+    BatchBroadcast {
+        messages: Vec<u64>,
+    },
+}
+
+/// Gossips every new value to its neighbors right away (challenges 3a to 3d).
+#[derive(Default)]
+pub struct Broadcast {
+    known: Mutex<BTreeSet<u64>>,
+    neighbors: Mutex<Vec<String>>,
+}
+
+impl Handler for Broadcast {
+    type Body = BroadcastBody;
+
+    async fn handle(self: Rc<Self>, node: Rc<Node>, msg: Message<BroadcastBody>) -> Result<()> {
+        match &msg.body.inner {
+            &BroadcastBody::Broadcast { message } => {
+                let already_seen = !self.known.lock().await.insert(message);
+                // Gossip to our neighbors, but only if we haven't seen this value before,
+                // to avoid infinite loops.
+                if !already_seen {
+                    for n in self.neighbors.lock().await.iter() {
+                        // Don't send the message back to the node we received it from.
+                        if *n == msg.src {
+                            continue;
+                        }
+                        let node = node.clone();
+                        let n = n.clone();
+                        task::spawn_local(async move {
+                            node.rpc::<_, BroadcastBody>(n, BroadcastBody::Broadcast { message })
+                                .await
+                        });
+                    }
+                }
+                let reply = node.reply(&msg, BroadcastBody::BroadcastOk);
+                node.send(&reply).await?;
+            }
+            BroadcastBody::Topology { .. } => {
+                *self.neighbors.lock().await = tree_neighbors(&node);
+                let reply = node.reply(&msg, BroadcastBody::TopologyOk);
+                node.send(&reply).await?;
+            }
+            BroadcastBody::Read => {
+                let reply = node.reply(
+                    &msg,
+                    BroadcastBody::ReadOk {
+                        messages: self.known.lock().await.clone().into_iter().collect(),
+                    },
+                );
+                node.send(&reply).await?;
+            }
+            _ => bail!(Error::from(ErrorCode::NotSupported)),
+        }
+
+        Ok(())
+    }
+}
+
+/// Gossips new values to its neighbors in batches (challenge 3e).
+#[derive(Default)]
+pub struct BatchedBroadcast {
+    known: Mutex<BTreeSet<u64>>,
+    neighbors: Mutex<Vec<String>>,
+    // Ordered, so that the batches go out in the same order in every (simulated) run.
+    next_batch: Mutex<BTreeMap<String, Vec<u64>>>,
+}
+
+impl BatchedBroadcast {
+    /// Periodically send the accumulated batches to our neighbors.
+    async fn send_batches(self: Rc<Self>, node: Rc<Node>) {
+        let start = Instant::now() + Duration::from_millis(150);
+        let mut batch_interval = time::interval_at(start, Duration::from_millis(150));
+        batch_interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            batch_interval.tick().await;
+            for (k, v) in self.next_batch.lock().await.iter_mut() {
+                let gossip = BroadcastBody::BatchBroadcast {
+                    messages: std::mem::take(v),
+                };
+                let node = node.clone();
+                let k = k.clone();
+                task::spawn_local(async move { node.rpc::<_, BroadcastBody>(k, gossip).await });
+            }
+        }
+    }
+}
+
+impl Handler for BatchedBroadcast {
+    type Body = BroadcastBody;
+
+    async fn init(self: Rc<Self>, node: Rc<Node>) -> Result<()> {
+        task::spawn_local(self.send_batches(node));
+        Ok(())
+    }
+
+    async fn handle(self: Rc<Self>, node: Rc<Node>, msg: Message<BroadcastBody>) -> Result<()> {
+        match &msg.body.inner {
+            &BroadcastBody::Broadcast { message } => {
+                // Mark value as seen. Only clients should send us a regular Broadcast message,
+                // and we assume that clients don't send duplicates.
+                let already_seen = !self.known.lock().await.insert(message);
+                debug_assert!(!already_seen);
+                // Add value to next batch, which will be sent asynchronously on a regular interval.
+                // Only clients should send us regular Broadcast messages,
+                // therefore we don't have to skip any of our neighbors for rebroadcast.
+                for n in self.neighbors.lock().await.iter() {
+                    self.next_batch
+                        .lock()
+                        .await
+                        .entry(n.clone())
+                        .and_modify(|e| e.push(message))
+                        .or_insert(vec![message]);
+                }
+                // NOTE: should we delay the BroadcastOk until we've _actually_ broadcasted
+                // the value to our neighbors? If so, this part should move to the batch send task.
+                let reply = node.reply(&msg, BroadcastBody::BroadcastOk);
+                node.send(&reply).await?;
+            }
+            BroadcastBody::BatchBroadcast { messages } => {
+                for &message in messages {
+                    let already_seen = !self.known.lock().await.insert(message);
+                    // New values should be added to the next broadcast batch.
+                    if !already_seen {
+                        for n in self.neighbors.lock().await.iter() {
+                            if *n == msg.src {
+                                // We don't need to send this value back to the node we got it from.
+                                continue;
+                            }
+                            self.next_batch
+                                .lock()
+                                .await
+                                .entry(n.clone())
+                                .and_modify(|e| e.push(message))
+                                .or_insert(vec![message]);
+                        }
+                    }
+                }
+                // NOTE: should we delay the BroadcastOk until we've _actually_ broadcasted
+                // the value to our neighbors? If so, this part should move to the batch send task.
+                let reply = node.reply(&msg, BroadcastBody::BroadcastOk);
+                node.send(&reply).await?;
+            }
+            BroadcastBody::Topology { .. } => {
+                *self.neighbors.lock().await = tree_neighbors(&node);
+                let reply = node.reply(&msg, BroadcastBody::TopologyOk);
+                node.send(&reply).await?;
+            }
+            BroadcastBody::Read => {
+                let reply = node.reply(
+                    &msg,
+                    BroadcastBody::ReadOk {
+                        messages: self.known.lock().await.clone().into_iter().collect(),
+                    },
+                );
+                node.send(&reply).await?;
+            }
+            _ => bail!(Error::from(ErrorCode::NotSupported)),
+        }
+
+        Ok(())
+    }
+}
+
+/// Our neighbors in a tree with a maximum of [`FANOUT`] children per node.
+///
+/// We ignore the topology suggestion from Maelstrom and build our own.
+fn tree_neighbors(node: &Node) -> Vec<String> {
+    let mut nodes = node.node_ids().to_vec();
+    nodes.sort();
+    let i = nodes
+        .binary_search_by(|n| n.as_str().cmp(node.id()))
+        .unwrap();
+    let mut children: &[String] = &[];
+    let child_idx = FANOUT * i + 1;
+    // Check that we are not a leaf node.
+    if child_idx < nodes.len() {
+        children = &nodes[child_idx..(child_idx + FANOUT).min(nodes.len())];
+    }
+    let mut parent: &[String] = &[];
+    if i != 0 {
+        // We are not the root node, so get our parent.
+        let parent_idx = (i - 1) / FANOUT;
+        parent = &nodes[parent_idx..(parent_idx + 1)];
+    }
+    let neighbors = [parent, children].concat();
+    debug_assert!(neighbors.len() <= FANOUT + 1);
+    neighbors
+}
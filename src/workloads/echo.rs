@@ -0,0 +1,34 @@
+use std::rc::Rc;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, ErrorCode, Handler, Message, Node};
+
+/// 1. Echo challenge
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum EchoBody {
+    Echo { echo: String },
+    EchoOk { echo: String },
+}
+
+/// Answers every `echo` request with the same text.
+pub struct Echo;
+
+impl Handler for Echo {
+    type Body = EchoBody;
+
+    async fn handle(self: Rc<Self>, node: Rc<Node>, msg: Message<EchoBody>) -> Result<()> {
+        match &msg.body.inner {
+            EchoBody::Echo { echo } => {
+                let reply = node.reply(&msg, EchoBody::EchoOk { echo: echo.clone() });
+                node.send(&reply).await?;
+            }
+            _ => bail!(Error::from(ErrorCode::NotSupported)),
+        }
+
+        Ok(())
+    }
+}
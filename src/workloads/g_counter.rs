@@ -0,0 +1,96 @@
+use std::rc::Rc;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, ErrorCode, Handler, KvClient, KvService, Message, Node};
+
+const COUNTER: &str = "counter";
+
+/// 4. Grow-Only Counter challenge
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum GCounterBody {
+    Add { delta: u64 },
+    AddOk,
+    Read,
+    ReadOk { value: u64 },
+}
+
+/// Keeps the counter in the sequentially consistent KV store.
+pub struct GCounter {
+    kv: KvClient,
+}
+
+impl Default for GCounter {
+    fn default() -> Self {
+        Self {
+            kv: KvClient::new(KvService::Seq),
+        }
+    }
+}
+
+impl GCounter {
+    /// Read the current value of the counter. A counter that hasn't been written yet is 0.
+    async fn read_counter(&self, node: &Node) -> Result<u64, Error> {
+        match self.kv.read(node, COUNTER).await {
+            Err(Error {
+                code: ErrorCode::KeyDoesNotExist,
+                ..
+            }) => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl Handler for GCounter {
+    type Body = GCounterBody;
+
+    async fn handle(self: Rc<Self>, node: Rc<Node>, msg: Message<GCounterBody>) -> Result<()> {
+        match &msg.body.inner {
+            GCounterBody::Read => {
+                // Reads from the sequentially consistent KV store might return stale values.
+                // We can prevent this by first issuing a unique write, which prevents the KV
+                // store from reordering our read in undesireable ways.
+                // (Although, as far as I understand it, the store _could_ still reorder the read
+                // without violating sequential consistency, but proving that this reordering is
+                // legal would be prohibitively expensive so it doesn't try to reorder. In any case,
+                // the Maelstrom provided seq-kv service seems to behave in this way so we make use of that.)
+                // Our message IDs are unique, so they make for a good unique value to write.
+                self.kv.write(&node, node.id(), &node.next_msg_id()).await?;
+                let value = self.read_counter(&node).await?;
+                let reply = node.reply(&msg, GCounterBody::ReadOk { value });
+                node.send(&reply).await?;
+            }
+            &GCounterBody::Add { delta } => {
+                // Apparently clients sometimes issue an Add request with a delta of 0,
+                // so let's check for that and skip contacting the KV store for those requests.
+                if delta != 0 {
+                    // To add to the counter we first need to get the current value
+                    // and then issue a CAS. If someone else changed the counter in the meantime,
+                    // the CAS fails and we have to try again with the new value.
+                    loop {
+                        let value = self.read_counter(&node).await?;
+                        match self
+                            .kv
+                            .cas_or_create(&node, COUNTER, &value, &(value + delta))
+                            .await
+                        {
+                            Ok(()) => break,
+                            Err(Error {
+                                code: ErrorCode::PreconditionFailed,
+                                ..
+                            }) => continue,
+                            Err(e) => return Err(e.into()),
+                        }
+                    }
+                }
+                let reply = node.reply(&msg, GCounterBody::AddOk);
+                node.send(&reply).await?;
+            }
+            _ => bail!(Error::from(ErrorCode::NotSupported)),
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1124 @@
+use std::cell::OnceCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::PathBuf;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Duration;
+use std::{env, fs, io, mem};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, Notify};
+use tokio::task;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use crate::{Error, ErrorCode, Handler, KvClient, KvService, Message, Node, RetryPolicy};
+
+mod disk;
+mod groups;
+mod log;
+mod replication;
+mod transactions;
+
+use groups::Group;
+use log::{Log, Sequence, TxnState};
+use replication::Leadership;
+
+/// 5. Kafka-Style Log challenge
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum KafkaBody {
+    Send {
+        key: String,
+        msg: u64,
+        /// What the message is about. Compaction keeps only the latest message for each of them.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        msg_key: Option<u64>,
+        /// Idempotent producers number their sends per key, with increasing sequence numbers.
+        /// A send that's already in the log is acknowledged with its original offset.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        producer_id: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    SendOk {
+        offset: u64,
+    },
+    Poll {
+        offsets: HashMap<String, u64>,
+        /// At most this many messages per key, if that's below the node's own limit.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_messages: Option<usize>,
+        /// At most this many bytes of messages in total, if that's below the node's own limit.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_bytes: Option<usize>,
+        /// How long to wait for new messages if there aren't any yet, in milliseconds.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_wait_ms: Option<u64>,
+    },
+    PollOk {
+        msgs: HashMap<String, Vec<(u64, u64)>>,
+    },
+    CommitOffsets {
+        offsets: HashMap<String, u64>,
+        /// The consumer group whose offsets these are, or the default group if there is none.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+    },
+    CommitOffsetsOk,
+    ListCommittedOffsets {
+        keys: Vec<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        group: Option<String>,
+    },
+    ListCommittedOffsetsOk {
+        offsets: HashMap<String, u64>,
+    },
+    /// Append messages to several keys at once: consumers see either all or none of them.
+    SendTxn {
+        msgs: Vec<(String, u64)>,
+    },
+    SendTxnOk {
+        /// The offsets of the messages, in the same order.
+        offsets: Vec<u64>,
+    },
+    // Custom messages for the members of consumer groups.
+    Heartbeat {
+        group: String,
+        member: String,
+        /// The keys that the member wants to consume.
+        keys: BTreeSet<String>,
+    },
+    HeartbeatOk {
+        /// Increases with every rebalance of the group.
+        generation: u64,
+        /// The keys that the member should consume until the next rebalance.
+        assigned: Vec<String>,
+    },
+    LeaveGroup {
+        group: String,
+        member: String,
+    },
+    LeaveGroupOk,
+    // Custom messages from the coordinator of a transaction to the leaders of its keys.
+    AppendTxn {
+        txn: u64,
+        msgs: Vec<(String, u64)>,
+    },
+    AppendTxnOk {
+        offsets: HashMap<String, Vec<u64>>,
+    },
+    EndTxn {
+        txn: u64,
+        keys: Vec<String>,
+        marker: Marker,
+    },
+    EndTxnOk,
+    // Custom messages to copy a key's log from its leader to its followers.
+    Replicate {
+        key: String,
+        epoch: u64,
+        /// The leader's low-water mark. Followers that are missing what's before it start over from there.
+        start: u64,
+        /// The offset from which on `msgs` holds all the leader's messages.
+        from: u64,
+        /// The epoch of the message before `from`, so that followers can tell whether they agree up to there.
+        prev_epoch: Option<u64>,
+        msgs: Vec<LogEntry>,
+        hw: u64,
+        committed: BTreeMap<String, u64>,
+    },
+    ReplicateOk {
+        /// The offset up to which the follower's log agrees with the leader's.
+        next: u64,
+    },
+}
+
+/// A message as it's stored in a log and copied to the other replicas.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LogEntry {
+    pub offset: u64,
+    /// The leader epoch in which the message was appended.
+    pub epoch: u64,
+    pub msg: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub msg_key: Option<u64>,
+    /// The id and sequence number of the idempotent producer that sent the message.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub producer: Option<(u64, u64)>,
+    /// The transaction that the message is part of, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub txn: Option<u64>,
+    /// Set on the control messages that end a transaction, which consumers never see.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub marker: Option<Marker>,
+}
+
+/// How a transaction ended.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Marker {
+    Commit,
+    Abort,
+}
+
+/// The maximum number of messages per key that we keep from polls that we forwarded.
+///
+/// The cache holds copies of the logs that other nodes lead, so without a cap it would keep
+/// growing over long runs just like the logs did before they were split into segments.
+const MAX_CACHED_MESSAGES: usize = 1000;
+
+/// The maximum number of messages in a single `Replicate`.
+const REPLICATION_BATCH: usize = 100;
+
+/// How often we look up the leader of a key again when forwarding a request fails.
+const MAX_ROUTING_ATTEMPTS: usize = 3;
+
+/// The consumer group of clients that don't name one.
+const DEFAULT_GROUP: &str = "";
+
+/// How often a poll that waits for new messages asks the leaders of other nodes' keys again.
+/// New messages for our own keys end the wait right away.
+const LONG_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How often retention and compaction go over the logs, and we look for abandoned transactions.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How the logs are replicated, stored and cleaned up.
+#[derive(Debug, Clone)]
+pub struct KafkaConfig {
+    /// The number of nodes that keep a copy of each key's log, including its leader.
+    /// With a single one, every key lives on its owner only and no lin-kv is needed.
+    pub replication: usize,
+    /// How long we wait for another node before treating it as unreachable.
+    /// Leaders drop unresponsive followers from the ISR, and in-sync followers
+    /// take over from leaders that don't respond to forwarded requests.
+    pub replica_timeout: Duration,
+    /// If set, every node writes its logs and offsets to a directory of its own in here,
+    /// and picks them up again when it's restarted with the same ID.
+    pub data_dir: Option<PathBuf>,
+    /// When the data written to `data_dir` is synced to disk.
+    pub fsync: FsyncPolicy,
+    /// When old messages are deleted.
+    pub retention: Retention,
+    /// Whether old messages are deleted once there's a later one with the same message key.
+    pub compaction: bool,
+    /// How long a consumer group member can go without a heartbeat before it's dropped from the group.
+    pub session_timeout: Duration,
+    /// The most that a poll returns, and the longest it waits.
+    pub poll: PollLimits,
+    /// How long a transaction can stay open in a log before its leader aborts it,
+    /// in case its coordinator has crashed.
+    pub txn_timeout: Duration,
+}
+
+impl Default for KafkaConfig {
+    /// No replication, and everything in memory.
+    fn default() -> Self {
+        Self {
+            replication: 1,
+            replica_timeout: Duration::from_millis(300),
+            data_dir: None,
+            fsync: FsyncPolicy::Always,
+            retention: Retention::default(),
+            compaction: false,
+            session_timeout: Duration::from_secs(3),
+            poll: PollLimits::default(),
+            txn_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// How much a poll returns, and how long it waits for new messages if there are none.
+/// Polls can ask for less, but not for more.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollLimits {
+    /// The maximum number of messages per key.
+    pub max_messages: usize,
+    /// The maximum size of all messages in a reply, as `[offset,msg]` in JSON.
+    /// Replies have at least one message if there is any though, so consumers always make progress.
+    pub max_bytes: Option<usize>,
+    /// The longest that a poll waits for new messages.
+    pub max_wait: Duration,
+}
+
+impl Default for PollLimits {
+    fn default() -> Self {
+        Self {
+            max_messages: 20,
+            max_bytes: None,
+            max_wait: Duration::from_secs(1),
+        }
+    }
+}
+
+impl PollLimits {
+    /// These limits, lowered to what a poll asks for. Polls that don't ask to wait don't.
+    fn narrow(
+        &self,
+        max_messages: Option<usize>,
+        max_bytes: Option<usize>,
+        max_wait: Option<Duration>,
+    ) -> Self {
+        Self {
+            max_messages: max_messages.map_or(self.max_messages, |max| max.min(self.max_messages)),
+            max_bytes: self.max_bytes.into_iter().chain(max_bytes).min(),
+            max_wait: max_wait.map_or(Duration::ZERO, |wait| wait.min(self.max_wait)),
+        }
+    }
+}
+
+/// When messages are deleted from the start of the logs.
+///
+/// Messages are deleted a whole segment at a time, so logs can hold a bit more than this.
+/// Messages that haven't been acknowledged to all in-sync replicas are never deleted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Retention {
+    /// Delete messages that are at least this old.
+    pub time: Option<Duration>,
+    /// Delete messages that have at least this many later ones.
+    pub messages: Option<u64>,
+    /// Whether messages are deleted even if no consumer has committed them yet.
+    pub uncommitted: bool,
+}
+
+impl Retention {
+    fn is_enabled(&self) -> bool {
+        self.time.is_some() || self.messages.is_some()
+    }
+}
+
+/// When the logs on disk are synced, i.e. how much can be lost if the machine crashes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// Before a message is acknowledged.
+    Always,
+    /// Every so often, in the background.
+    Interval(Duration),
+    /// Whenever the operating system decides to.
+    Never,
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = anyhow::Error;
+
+    /// `always`, `never`, or the interval in milliseconds.
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "always" => FsyncPolicy::Always,
+            "never" => FsyncPolicy::Never,
+            ms => FsyncPolicy::Interval(Duration::from_millis(
+                ms.parse()
+                    .context("expected always, never, or a number of milliseconds")?,
+            )),
+        })
+    }
+}
+
+impl KafkaConfig {
+    /// The default configuration, changed by the `KAFKA_REPLICATION`, `KAFKA_REPLICA_TIMEOUT_MS`,
+    /// `KAFKA_DATA_DIR`, `KAFKA_FSYNC`, `KAFKA_RETENTION_MS`, `KAFKA_RETENTION_MESSAGES`,
+    /// `KAFKA_RETENTION_UNCOMMITTED`, `KAFKA_COMPACTION`, `KAFKA_SESSION_TIMEOUT_MS`,
+    /// `KAFKA_MAX_POLL_MESSAGES`, `KAFKA_MAX_POLL_BYTES`, `KAFKA_MAX_POLL_WAIT_MS` and
+    /// `KAFKA_TXN_TIMEOUT_MS` environment variables if they are set.
+    pub fn from_env() -> Result<Self> {
+        let mut config = Self::default();
+        if let Ok(replication) = env::var("KAFKA_REPLICATION") {
+            config.replication = replication
+                .parse()
+                .context("KAFKA_REPLICATION must be a number")?;
+        }
+        if let Ok(timeout) = env::var("KAFKA_REPLICA_TIMEOUT_MS") {
+            let timeout = timeout
+                .parse()
+                .context("KAFKA_REPLICA_TIMEOUT_MS must be a number")?;
+            config.replica_timeout = Duration::from_millis(timeout);
+        }
+        if let Some(data_dir) = env::var_os("KAFKA_DATA_DIR") {
+            config.data_dir = Some(data_dir.into());
+        }
+        if let Ok(fsync) = env::var("KAFKA_FSYNC") {
+            config.fsync = fsync.parse().context("invalid KAFKA_FSYNC")?;
+        }
+        if let Ok(time) = env::var("KAFKA_RETENTION_MS") {
+            let time = time
+                .parse()
+                .context("KAFKA_RETENTION_MS must be a number")?;
+            config.retention.time = Some(Duration::from_millis(time));
+        }
+        if let Ok(messages) = env::var("KAFKA_RETENTION_MESSAGES") {
+            let messages = messages
+                .parse()
+                .context("KAFKA_RETENTION_MESSAGES must be a number")?;
+            config.retention.messages = Some(messages);
+        }
+        if let Ok(uncommitted) = env::var("KAFKA_RETENTION_UNCOMMITTED") {
+            config.retention.uncommitted = uncommitted
+                .parse()
+                .context("KAFKA_RETENTION_UNCOMMITTED must be true or false")?;
+        }
+        if let Ok(compaction) = env::var("KAFKA_COMPACTION") {
+            config.compaction = compaction
+                .parse()
+                .context("KAFKA_COMPACTION must be true or false")?;
+        }
+        if let Ok(timeout) = env::var("KAFKA_SESSION_TIMEOUT_MS") {
+            let timeout = timeout
+                .parse()
+                .context("KAFKA_SESSION_TIMEOUT_MS must be a number")?;
+            config.session_timeout = Duration::from_millis(timeout);
+        }
+        if let Ok(max) = env::var("KAFKA_MAX_POLL_MESSAGES") {
+            config.poll.max_messages = max
+                .parse()
+                .context("KAFKA_MAX_POLL_MESSAGES must be a number")?;
+        }
+        if let Ok(max) = env::var("KAFKA_MAX_POLL_BYTES") {
+            let max = max
+                .parse()
+                .context("KAFKA_MAX_POLL_BYTES must be a number")?;
+            config.poll.max_bytes = Some(max);
+        }
+        if let Ok(wait) = env::var("KAFKA_MAX_POLL_WAIT_MS") {
+            let wait = wait
+                .parse()
+                .context("KAFKA_MAX_POLL_WAIT_MS must be a number")?;
+            config.poll.max_wait = Duration::from_millis(wait);
+        }
+        if let Ok(timeout) = env::var("KAFKA_TXN_TIMEOUT_MS") {
+            let timeout = timeout
+                .parse()
+                .context("KAFKA_TXN_TIMEOUT_MS must be a number")?;
+            config.txn_timeout = Duration::from_millis(timeout);
+        }
+        Ok(config)
+    }
+}
+
+/// What a node knows about the log of a single key.
+#[derive(Debug, Default)]
+struct Partition {
+    log: Log,
+    /// Every message below the high-water mark is on all in-sync replicas,
+    /// so it survives a failover and can be handed out to consumers.
+    hw: u64,
+    /// The committed offset of every consumer group.
+    committed: BTreeMap<String, u64>,
+    /// The latest leader epoch that we've heard of.
+    epoch: u64,
+    /// Who we think leads the key, or `None` if we have to look it up.
+    leadership: Option<Leadership>,
+    /// While we lead the key: the offset up to which each follower's log agrees with ours.
+    progress: HashMap<String, u64>,
+    /// While we lead the key: the replicas outside of the ISR that we're bringing up to date.
+    catching_up: HashSet<String>,
+    /// While we lead the key: the replicas that are up to date and that we're adding to the ISR.
+    /// Until lin-kv has the outcome, the high-water mark only moves once they have the messages too.
+    joining: BTreeSet<String>,
+    /// Since when we've seen each of the open transactions in the log, to abort the abandoned ones.
+    open_since: HashMap<u64, Instant>,
+}
+
+/// A multi-node log (challenges 5b and 5c) where every key is led by a single node.
+///
+/// The leader of a key is the only one to allocate offsets, so offsets are gap-free without
+/// any coordination. It starts out as the key's owner, and with [`KafkaConfig::replication`]
+/// above one, the log is copied to the next nodes in ID order, which can take over if the
+/// leader becomes unreachable. Sends are acknowledged once the message
+/// is on all in-sync replicas, and polls only return messages that are.
+/// The other nodes forward requests for the key to its leader, and keep the messages
+/// they have seen, since those never change once they have been polled.
+/// With [`KafkaConfig::data_dir`], logs and offsets are also written to disk.
+/// Old messages can be deleted with [`KafkaConfig::retention`] and compacted with
+/// [`KafkaConfig::compaction`], after which polls skip their offsets.
+/// Transactions append to several keys at once, and polls only return their messages once
+/// they're committed.
+pub struct Kafka {
+    config: KafkaConfig,
+    /// Where the leadership of the keys is kept when they are replicated.
+    kv: KvClient,
+    partitions: Mutex<HashMap<String, Partition>>,
+    cache: Mutex<HashMap<String, BTreeMap<u64, u64>>>,
+    /// The consumer groups that we coordinate.
+    groups: Mutex<HashMap<String, Group>>,
+    /// Wakes up polls that wait for new messages whenever a high-water mark moves.
+    advanced: Notify,
+    /// Our own directory in `config.data_dir`, once we know our ID.
+    dir: OnceCell<PathBuf>,
+}
+
+impl Default for Kafka {
+    fn default() -> Self {
+        Self::new(KafkaConfig::default())
+    }
+}
+
+impl Kafka {
+    pub fn new(config: KafkaConfig) -> Self {
+        let kv_policy = RetryPolicy {
+            timeout: Some(config.replica_timeout),
+            ..RetryPolicy::default()
+        };
+        Self {
+            kv: KvClient::new(KvService::Lin).with_retry_policy(kv_policy),
+            config,
+            partitions: Mutex::default(),
+            cache: Mutex::default(),
+            groups: Mutex::default(),
+            advanced: Notify::new(),
+            dir: OnceCell::new(),
+        }
+    }
+
+    /// The partition of `key`, which starts out empty.
+    fn partition<'a>(
+        &self,
+        partitions: &'a mut HashMap<String, Partition>,
+        key: &str,
+    ) -> &'a mut Partition {
+        partitions
+            .entry(key.to_owned())
+            .or_insert_with(|| Partition {
+                log: Log::new(self.dir.get().map(|dir| dir.join(disk::key_dir_name(key)))),
+                ..Partition::default()
+            })
+    }
+
+    /// Load the logs and offsets that a previous run of this node left in its directory.
+    async fn recover(&self) -> Result<()> {
+        let Some(dir) = self.dir.get() else {
+            return Ok(());
+        };
+        let checkpoints: HashMap<String, Checkpoint> = match fs::read(dir.join(OFFSETS_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes).context("corrupt offsets file")?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let mut partitions = self.partitions.lock().await;
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries.collect::<io::Result<Vec<_>>>()?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let name = entry.file_name();
+            let Some(key) = name.to_str().and_then(disk::key_from_dir_name) else {
+                continue;
+            };
+            if entry.file_type()?.is_dir() {
+                let log = Log::open(entry.path())?;
+                self.partition(&mut partitions, &key).log = log;
+            }
+        }
+        for (key, checkpoint) in checkpoints {
+            let partition = self.partition(&mut partitions, &key);
+            partition.committed = checkpoint.committed;
+            partition.hw = checkpoint.hw;
+        }
+        for partition in partitions.values_mut() {
+            // Without followers, a message is acknowledged as soon as it's in the log.
+            if self.config.replication <= 1 {
+                partition.hw = partition.log.len();
+            }
+            partition.hw = partition.hw.min(partition.log.len());
+        }
+        Ok(())
+    }
+
+    /// Write the committed offsets and high-water marks of all keys to our directory, if we have one.
+    fn checkpoint(&self, partitions: &HashMap<String, Partition>) -> io::Result<()> {
+        let Some(dir) = self.dir.get() else {
+            return Ok(());
+        };
+        let checkpoints: BTreeMap<&String, Checkpoint> = partitions
+            .iter()
+            .map(|(key, partition)| {
+                let checkpoint = Checkpoint {
+                    committed: partition.committed.clone(),
+                    hw: partition.hw,
+                };
+                (key, checkpoint)
+            })
+            .collect();
+        let contents = serde_json::to_vec(&checkpoints)?;
+        fs::create_dir_all(dir)?;
+        let sync = self.config.fsync != FsyncPolicy::Never;
+        disk::write_atomically(&dir.join(OFFSETS_FILE), &contents, sync)
+    }
+
+    /// Periodically sync the logs and write the offsets to disk.
+    async fn sync_periodically(self: Rc<Self>, period: Duration) {
+        let mut interval = time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let mut partitions = self.partitions.lock().await;
+            let synced = partitions
+                .values_mut()
+                .try_for_each(|partition| partition.log.sync())
+                .and_then(|()| self.checkpoint(&partitions));
+            if let Err(e) = synced {
+                eprintln!("Failed to sync the logs: {e}");
+            }
+        }
+    }
+
+    /// Periodically delete old messages and compact the logs.
+    ///
+    /// Every replica does so on its own, but they only ever touch messages below their
+    /// high-water mark, on which all replicas agree.
+    async fn clean_periodically(self: Rc<Self>) {
+        let mut interval = time::interval_at(Instant::now() + CLEANUP_INTERVAL, CLEANUP_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let retention = &self.config.retention;
+        loop {
+            interval.tick().await;
+            let mut partitions = self.partitions.lock().await;
+            let cleaned = partitions.values_mut().try_for_each(|partition| {
+                // Committed offsets are the last message that a consumer has processed,
+                // and we wait for the slowest consumer group.
+                let limit = match partition.committed.values().min() {
+                    _ if retention.uncommitted => partition.hw,
+                    Some(committed) => partition.hw.min(committed + 1),
+                    None => 0,
+                };
+                // Messages of undecided transactions might still be committed.
+                let limit = limit.min(partition.log.stable_offset(partition.hw));
+                partition
+                    .log
+                    .apply_retention(retention.time, retention.messages, limit)?;
+                if self.config.compaction {
+                    partition.log.compact(partition.hw)?;
+                }
+                Ok::<_, io::Error>(())
+            });
+            if let Err(e) = cleaned {
+                eprintln!("Failed to clean up the logs: {e}");
+            }
+        }
+    }
+
+    /// How we retry messages between the replicas of a key.
+    fn replica_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: self.config.replica_timeout / 3,
+            timeout: Some(self.config.replica_timeout),
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// How we retry requests that we forward to the leader of a key.
+    /// The leader might have to wait for its followers before it can answer.
+    fn forward_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: self.config.replica_timeout,
+            timeout: Some(self.config.replica_timeout * 3),
+            ..RetryPolicy::default()
+        }
+    }
+
+    /// How we forward requests that must not be applied twice: once, waiting as long as
+    /// [`Kafka::forward_policy`] does for all of its attempts.
+    fn forward_once_policy(&self) -> RetryPolicy {
+        RetryPolicy {
+            initial_backoff: self.config.replica_timeout * 3,
+            max_attempts: Some(1),
+            ..self.forward_policy()
+        }
+    }
+
+    /// The nodes that keep a copy of `key`: its owner and the nodes after it in ID order.
+    /// They are the same on every node, since all nodes know the same `node_ids`.
+    fn replicas(&self, node: &Node, key: &str) -> Vec<String> {
+        let hash = fnv1a(key.bytes());
+        let mut node_ids = node.node_ids().to_vec();
+        node_ids.sort();
+        let owner = (hash % node_ids.len() as u64) as usize;
+        node_ids.rotate_left(owner);
+        node_ids.truncate(self.config.replication.clamp(1, node_ids.len()));
+        node_ids
+    }
+
+    /// Send `request(keys)` to the leaders of `keys`, and return the keys that we lead
+    /// ourselves, in order, along with the replies of the other leaders.
+    ///
+    /// If a leader doesn't respond, we try to take over the keys, and then ask their new leader.
+    /// Requests that must not be applied twice (`retry_timeouts` is false) are only sent once,
+    /// and fail if the leader doesn't answer in time.
+    /// Requests that were forwarded to us aren't forwarded again, to avoid cycles between
+    /// nodes with different views of the leadership: the sender will look it up again.
+    async fn route(
+        self: &Rc<Self>,
+        node: &Rc<Node>,
+        src: &str,
+        keys: Vec<String>,
+        retry_timeouts: bool,
+        request: impl Fn(Vec<String>) -> KafkaBody,
+    ) -> Result<(Vec<String>, Vec<KafkaBody>), Error> {
+        let forwarded = node.node_ids().iter().any(|id| id == src);
+        let policy = if retry_timeouts {
+            self.forward_policy()
+        } else {
+            self.forward_once_policy()
+        };
+        let mut local = Vec::new();
+        let mut replies = Vec::new();
+        let mut pending: BTreeSet<String> = keys.into_iter().collect();
+        for _ in 0..MAX_ROUTING_ATTEMPTS {
+            let mut by_leader: BTreeMap<String, Vec<(String, Leadership)>> = BTreeMap::new();
+            for key in mem::take(&mut pending) {
+                let leadership = self.leadership(node, &key).await?;
+                if leadership.leader == node.id() {
+                    local.push(key);
+                } else if forwarded {
+                    return Err(Error::new(
+                        ErrorCode::TemporarilyUnavailable,
+                        format!("{} leads {key}", leadership.leader),
+                    ));
+                } else {
+                    by_leader
+                        .entry(leadership.leader.clone())
+                        .or_default()
+                        .push((key, leadership));
+                }
+            }
+
+            for (leader, keys) in by_leader {
+                let request = request(keys.iter().map(|(key, _)| key.clone()).collect());
+                match node.rpc_with(&leader, request, &policy).await {
+                    Ok(reply) => replies.push(reply.body.inner),
+                    // The node doesn't lead (all of) the keys anymore.
+                    Err(Error {
+                        code: ErrorCode::TemporarilyUnavailable,
+                        ..
+                    }) => {
+                        for (key, _) in keys {
+                            self.forget(&key).await;
+                            pending.insert(key);
+                        }
+                    }
+                    Err(
+                        e @ Error {
+                            code: ErrorCode::Timeout,
+                            ..
+                        },
+                    ) => {
+                        for (key, leadership) in &keys {
+                            self.fail_over(node, key, leadership).await?;
+                        }
+                        if !retry_timeouts {
+                            return Err(e);
+                        }
+                        pending.extend(keys.into_iter().map(|(key, _)| key));
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            if pending.is_empty() {
+                return Ok((local, replies));
+            }
+        }
+        let pending: Vec<String> = pending.into_iter().collect();
+        Err(Error::new(
+            ErrorCode::Timeout,
+            format!("found no leader for {}", pending.join(", ")),
+        ))
+    }
+
+    /// Apply `request` to `group` if we coordinate it, or forward it to the node that does.
+    /// That's the node that would own a key of the same name.
+    async fn coordinate(
+        &self,
+        node: &Node,
+        group: &str,
+        request: &KafkaBody,
+        apply: impl FnOnce(&mut Group) -> KafkaBody,
+    ) -> Result<KafkaBody, Error> {
+        let coordinator = self.replicas(node, group).swap_remove(0);
+        if coordinator == node.id() {
+            let mut groups = self.groups.lock().await;
+            return Ok(apply(groups.entry(group.to_owned()).or_default()));
+        }
+        let reply = node
+            .rpc_with(&coordinator, request.clone(), &self.forward_policy())
+            .await?;
+        Ok(reply.body.inner)
+    }
+
+    /// Read the messages from `offsets` on, within `limits`: from our cache, from our own logs,
+    /// and from the leaders of the other keys.
+    async fn poll(
+        self: &Rc<Self>,
+        node: &Rc<Node>,
+        src: &str,
+        offsets: &HashMap<String, u64>,
+        limits: &PollLimits,
+    ) -> Result<HashMap<String, Vec<(u64, u64)>>> {
+        let mut msgs: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+        // Only ask the leaders for what comes after the messages we already have.
+        let mut remaining = BTreeMap::new();
+        for (k, &o) in offsets {
+            let cached = self.cached(k, o, limits.max_messages).await;
+            if cached.len() < limits.max_messages {
+                remaining.insert(k.clone(), o + cached.len() as u64);
+            }
+            if !cached.is_empty() {
+                msgs.insert(k.clone(), cached);
+            }
+        }
+        let keys = remaining.keys().cloned().collect();
+        // The leaders don't wait: we ask them again if nothing turns up.
+        let request = |keys: Vec<String>| KafkaBody::Poll {
+            offsets: keys
+                .into_iter()
+                .map(|k| (k.clone(), remaining[&k]))
+                .collect(),
+            max_messages: Some(limits.max_messages),
+            max_bytes: limits.max_bytes,
+            max_wait_ms: None,
+        };
+        let (local, replies) = self.route(node, src, keys, true, request).await?;
+
+        let mut fetched = HashMap::new();
+        let partitions = self.partitions.lock().await;
+        for k in local {
+            // Clients sometimes poll for logs that we don't know about.
+            let known = partitions.get(&k).filter(|p| p.log.len() > 0);
+            if let Some(partition) = known {
+                let read = partition
+                    .log
+                    .read(remaining[&k], partition.hw, limits.max_messages)?;
+                fetched.insert(k, read);
+            }
+        }
+        drop(partitions);
+        let mut cache = self.cache.lock().await;
+        for reply in replies {
+            let KafkaBody::PollOk { msgs } = reply else {
+                bail!("unexpected reply to forwarded poll: {reply:?}");
+            };
+            for (k, msgs) in msgs {
+                let cached = cache.entry(k.clone()).or_default();
+                cached.extend(msgs.iter().copied());
+                // Consumers move forward, so the oldest messages are the least useful.
+                while cached.len() > MAX_CACHED_MESSAGES {
+                    cached.pop_first();
+                }
+                fetched.insert(k, msgs);
+            }
+        }
+        for (k, fetched) in fetched {
+            let messages = msgs.entry(k).or_default();
+            messages.extend(fetched);
+            messages.truncate(limits.max_messages);
+        }
+        if let Some(max_bytes) = limits.max_bytes {
+            limit_bytes(&mut msgs, max_bytes);
+        }
+        Ok(msgs)
+    }
+
+    /// Look up as many messages of `key` from `from` on as we have cached without a gap, up to `limit`.
+    async fn cached(&self, key: &str, from: u64, limit: usize) -> Vec<(u64, u64)> {
+        let cache = self.cache.lock().await;
+        let Some(log) = cache.get(key) else {
+            return Vec::new();
+        };
+        log.range(from..)
+            .zip(from..)
+            .take_while(|((&offset, _), expected)| offset == *expected)
+            .take(limit)
+            .map(|((&offset, &msg), _)| (offset, msg))
+            .collect()
+    }
+}
+
+/// The name of the file in which the committed offsets and high-water marks are kept.
+const OFFSETS_FILE: &str = "offsets.json";
+
+/// What we remember about a key besides its log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Checkpoint {
+    committed: BTreeMap<String, u64>,
+    hw: u64,
+}
+
+/// A hand-rolled FNV-1a, for hashes that have to be stable across nodes and runs.
+fn fnv1a(bytes: impl IntoIterator<Item = u8>) -> u64 {
+    bytes.into_iter().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn io_error(e: io::Error) -> Error {
+    Error::new(ErrorCode::Crash, format!("I/O error: {e}"))
+}
+
+/// Cut `msgs` down to `max_bytes` in total, going through the keys in order.
+/// The first message stays even if it's larger, so that consumers always make progress.
+fn limit_bytes(msgs: &mut HashMap<String, Vec<(u64, u64)>>, max_bytes: usize) {
+    let mut keys: Vec<String> = msgs.keys().cloned().collect();
+    keys.sort();
+    let mut left = max_bytes;
+    let mut first = true;
+    for key in keys {
+        let messages = msgs.get_mut(&key).expect("we got the key from the map");
+        let mut kept = 0;
+        for &(offset, msg) in messages.iter() {
+            // `[offset,msg]`
+            let size = decimal_digits(offset) + decimal_digits(msg) + 3;
+            if size > left && !first {
+                break;
+            }
+            left = left.saturating_sub(size);
+            kept += 1;
+            first = false;
+        }
+        messages.truncate(kept);
+    }
+}
+
+fn decimal_digits(n: u64) -> usize {
+    n.checked_ilog10().map_or(1, |log| log as usize + 1)
+}
+
+/// Whatever went wrong after we changed our log, the change might still take effect.
+fn indefinite(e: Error) -> Error {
+    if e.is_definite() {
+        Error::new(ErrorCode::Crash, e.to_string())
+    } else {
+        e
+    }
+}
+
+impl Handler for Kafka {
+    type Body = KafkaBody;
+
+    async fn init(self: Rc<Self>, node: Rc<Node>) -> Result<()> {
+        if let Some(data_dir) = &self.config.data_dir {
+            let _ = self.dir.set(data_dir.join(node.id()));
+            self.recover().await?;
+            if let FsyncPolicy::Interval(period) = self.config.fsync {
+                task::spawn_local(self.clone().sync_periodically(period));
+            }
+        }
+        if self.config.retention.is_enabled() || self.config.compaction {
+            task::spawn_local(self.clone().clean_periodically());
+        }
+        task::spawn_local(self.clone().resolve_periodically(node));
+        Ok(())
+    }
+
+    async fn handle(self: Rc<Self>, node: Rc<Node>, msg: Message<KafkaBody>) -> Result<()> {
+        match &msg.body.inner {
+            KafkaBody::Send {
+                key,
+                msg: m,
+                msg_key,
+                producer_id,
+                seq,
+            } => {
+                let producer = match (producer_id, seq) {
+                    (Some(id), Some(seq)) => Some((*id, *seq)),
+                    (None, None) => None,
+                    _ => bail!(Error::new(
+                        ErrorCode::MalformedRequest,
+                        "producer_id and seq go together"
+                    )),
+                };
+                let request = |_| msg.body.inner.clone();
+                // Idempotent sends can safely be tried again.
+                let (local, replies) = self
+                    .route(
+                        &node,
+                        &msg.src,
+                        vec![key.clone()],
+                        producer.is_some(),
+                        request,
+                    )
+                    .await?;
+                let offset = if local.is_empty() {
+                    match replies.into_iter().next() {
+                        Some(KafkaBody::SendOk { offset }) => offset,
+                        other => bail!("unexpected reply to forwarded send: {other:?}"),
+                    }
+                } else {
+                    let offset = {
+                        let mut partitions = self.partitions.lock().await;
+                        let partition = self.partition(&mut partitions, key);
+                        let sequence =
+                            producer.map(|(id, seq)| (id, seq, partition.log.sequence(id, seq)));
+                        let offset = match sequence {
+                            // It might not be on all replicas yet, so we replicate it below.
+                            Some((_, _, Sequence::Duplicate(offset))) => offset,
+                            Some((id, seq, Sequence::Stale)) => bail!(Error::new(
+                                ErrorCode::PreconditionFailed,
+                                format!("send {seq} of producer {id} is older than the ones we remember"),
+                            )),
+                            Some((_, _, Sequence::New)) | None => partition.log.append(
+                                *m,
+                                *msg_key,
+                                producer,
+                                partition.epoch,
+                            )?,
+                        };
+                        if self.config.fsync == FsyncPolicy::Always {
+                            partition.log.sync()?;
+                        }
+                        offset
+                    };
+                    self.replicate(&node, key).await.map_err(indefinite)?;
+                    offset
+                };
+                let reply = node.reply(&msg, KafkaBody::SendOk { offset });
+                node.send(&reply).await?;
+            }
+            KafkaBody::Poll {
+                offsets,
+                max_messages,
+                max_bytes,
+                max_wait_ms,
+            } => {
+                let max_wait = max_wait_ms.map(Duration::from_millis);
+                let limits = self.config.poll.narrow(*max_messages, *max_bytes, max_wait);
+                let deadline = Instant::now() + limits.max_wait;
+                let msgs = loop {
+                    // Listen before reading, so that we can't miss a message in between.
+                    let advanced = self.advanced.notified();
+                    tokio::pin!(advanced);
+                    advanced.as_mut().enable();
+                    let msgs = self.poll(&node, &msg.src, offsets, &limits).await?;
+                    let now = Instant::now();
+                    if msgs.values().any(|msgs| !msgs.is_empty()) || now >= deadline {
+                        break msgs;
+                    }
+                    let _ =
+                        time::timeout_at(deadline.min(now + LONG_POLL_INTERVAL), advanced).await;
+                };
+                let reply = node.reply(&msg, KafkaBody::PollOk { msgs });
+                node.send(&reply).await?;
+            }
+            KafkaBody::CommitOffsets { offsets, group } => {
+                let keys = offsets.keys().cloned().collect();
+                let request = |keys: Vec<String>| KafkaBody::CommitOffsets {
+                    offsets: keys.into_iter().map(|k| (k.clone(), offsets[&k])).collect(),
+                    group: group.clone(),
+                };
+                let group = group.as_deref().unwrap_or(DEFAULT_GROUP);
+                let (local, _) = self.route(&node, &msg.src, keys, true, request).await?;
+                if !local.is_empty() {
+                    let mut partitions = self.partitions.lock().await;
+                    for k in &local {
+                        let partition = self.partition(&mut partitions, k);
+                        // Committed offsets only ever move forward.
+                        let committed = partition.committed.entry(group.to_owned()).or_default();
+                        *committed = (*committed).max(offsets[k]);
+                    }
+                    self.checkpoint(&partitions)?;
+                }
+                for k in local {
+                    self.replicate(&node, &k).await.map_err(indefinite)?;
+                }
+                let reply = node.reply(&msg, KafkaBody::CommitOffsetsOk);
+                node.send(&reply).await?;
+            }
+            KafkaBody::ListCommittedOffsets { keys, group } => {
+                let mut offsets = HashMap::new();
+                let request = |keys| KafkaBody::ListCommittedOffsets {
+                    keys,
+                    group: group.clone(),
+                };
+                let group = group.as_deref().unwrap_or(DEFAULT_GROUP);
+                let (local, replies) = self
+                    .route(&node, &msg.src, keys.clone(), true, request)
+                    .await?;
+                let partitions = self.partitions.lock().await;
+                for k in local {
+                    if let Some(committed) = partitions.get(&k).and_then(|p| p.committed.get(group))
+                    {
+                        offsets.insert(k, *committed);
+                    }
+                }
+                drop(partitions);
+                for reply in replies {
+                    let KafkaBody::ListCommittedOffsetsOk { offsets: listed } = reply else {
+                        bail!("unexpected reply to forwarded list: {reply:?}");
+                    };
+                    offsets.extend(listed);
+                }
+                let reply = node.reply(&msg, KafkaBody::ListCommittedOffsetsOk { offsets });
+                node.send(&reply).await?;
+            }
+            KafkaBody::SendTxn { msgs } => {
+                let offsets = self.send_txn(&node, &msg.src, msgs).await?;
+                let reply = node.reply(&msg, KafkaBody::SendTxnOk { offsets });
+                node.send(&reply).await?;
+            }
+            KafkaBody::AppendTxn { txn, msgs } => {
+                let offsets = self.append_txn(&node, &msg.src, *txn, msgs).await?;
+                let reply = node.reply(&msg, KafkaBody::AppendTxnOk { offsets });
+                node.send(&reply).await?;
+            }
+            KafkaBody::EndTxn { txn, keys, marker } => {
+                self.end_txn(&node, &msg.src, *txn, keys.clone(), *marker)
+                    .await?;
+                node.send(&node.reply(&msg, KafkaBody::EndTxnOk)).await?;
+            }
+            KafkaBody::Replicate {
+                key,
+                epoch,
+                start,
+                from,
+                prev_epoch,
+                msgs,
+                hw,
+                committed,
+            } => {
+                let next = self
+                    .apply_replicate(
+                        key,
+                        *epoch,
+                        *start,
+                        *from,
+                        *prev_epoch,
+                        msgs,
+                        *hw,
+                        committed,
+                    )
+                    .await?;
+                let reply = node.reply(&msg, KafkaBody::ReplicateOk { next });
+                node.send(&reply).await?;
+            }
+            KafkaBody::Heartbeat {
+                group,
+                member,
+                keys,
+            } => {
+                let reply = self
+                    .coordinate(&node, group, &msg.body.inner, |group| {
+                        let (generation, assigned) =
+                            group.heartbeat(member, keys.clone(), self.config.session_timeout);
+                        KafkaBody::HeartbeatOk {
+                            generation,
+                            assigned,
+                        }
+                    })
+                    .await?;
+                node.send(&node.reply(&msg, reply)).await?;
+            }
+            KafkaBody::LeaveGroup { group, member } => {
+                let reply = self
+                    .coordinate(&node, group, &msg.body.inner, |group| {
+                        group.leave(member);
+                        KafkaBody::LeaveGroupOk
+                    })
+                    .await?;
+                node.send(&node.reply(&msg, reply)).await?;
+            }
+            _ => bail!(Error::from(ErrorCode::NotSupported)),
+        }
+
+        Ok(())
+    }
+}
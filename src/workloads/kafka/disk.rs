@@ -0,0 +1,325 @@
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::{fnv1a, LogEntry, Marker};
+
+/// The size of a record in a segment file: offset, epoch, message, flags, message key,
+/// producer id, sequence number, transaction and a checksum, all little-endian.
+const RECORD_SIZE: usize = 72;
+
+/// Flag for records with a message key.
+const HAS_MSG_KEY: u64 = 1;
+
+/// Flag for records with a producer id and sequence number.
+const HAS_PRODUCER: u64 = 2;
+
+/// Flag for records that are part of a transaction.
+const HAS_TXN: u64 = 4;
+
+/// Flags for the markers that commit and abort a transaction.
+const COMMIT: u64 = 8;
+const ABORT: u64 = 16;
+
+fn encode(entry: &LogEntry) -> [u8; RECORD_SIZE] {
+    let mut flags = 0;
+    if entry.msg_key.is_some() {
+        flags |= HAS_MSG_KEY;
+    }
+    if entry.producer.is_some() {
+        flags |= HAS_PRODUCER;
+    }
+    if entry.txn.is_some() {
+        flags |= HAS_TXN;
+    }
+    match entry.marker {
+        Some(Marker::Commit) => flags |= COMMIT,
+        Some(Marker::Abort) => flags |= ABORT,
+        None => {}
+    }
+    let (producer_id, seq) = entry.producer.unwrap_or_default();
+    let words = [
+        entry.offset,
+        entry.epoch,
+        entry.msg,
+        flags,
+        entry.msg_key.unwrap_or(0),
+        producer_id,
+        seq,
+        entry.txn.unwrap_or(0),
+    ];
+    let mut bytes = [0; RECORD_SIZE];
+    for (chunk, word) in bytes.chunks_exact_mut(8).zip(words) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    let checksum = fnv1a(bytes[..64].iter().copied());
+    bytes[64..72].copy_from_slice(&checksum.to_le_bytes());
+    bytes
+}
+
+/// `None` if the checksum doesn't match, e.g. because the write was torn.
+fn decode(bytes: &[u8]) -> Option<LogEntry> {
+    let word = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+    if fnv1a(bytes[..64].iter().copied()) != word(64) {
+        return None;
+    }
+    let flags = word(24);
+    Some(LogEntry {
+        offset: word(0),
+        epoch: word(8),
+        msg: word(16),
+        msg_key: (flags & HAS_MSG_KEY != 0).then(|| word(32)),
+        producer: (flags & HAS_PRODUCER != 0).then(|| (word(40), word(48))),
+        txn: (flags & HAS_TXN != 0).then(|| word(56)),
+        marker: match flags & (COMMIT | ABORT) {
+            COMMIT => Some(Marker::Commit),
+            ABORT => Some(Marker::Abort),
+            _ => None,
+        },
+    })
+}
+
+/// The base offset of a segment, and its messages.
+pub(super) type Segment = (u64, Vec<LogEntry>);
+
+/// The name of the file in which a log that was reset keeps the offset it continues at.
+const START_FILE: &str = "start";
+
+/// The files of a log on disk: a directory with one append-only file per segment,
+/// named after the segment's base offset, and the offset that the log starts at
+/// if it was reset (see [`LogDir::reset`]).
+///
+/// Nothing is created on disk until the first message is appended or the log is reset.
+#[derive(Debug)]
+pub(super) struct LogDir {
+    path: PathBuf,
+    /// The file of the active segment, with its base offset.
+    active: Option<(u64, File)>,
+    /// Whether there are writes that haven't been synced yet.
+    dirty: bool,
+}
+
+impl LogDir {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            active: None,
+            dirty: false,
+        }
+    }
+
+    /// Read the messages of all segments, along with their base offsets,
+    /// and the offset that the log continues at if it was reset.
+    ///
+    /// Recovery stops at the first record that is incomplete, fails its checksum, or doesn't
+    /// come after the ones before it. That record and everything after it is left over
+    /// from a write that was torn by a crash, so the files are truncated there.
+    /// Offsets can skip ahead though, where messages were deleted or compacted.
+    pub fn recover(path: PathBuf) -> io::Result<(Self, Vec<Segment>, Option<u64>)> {
+        let dir = Self::new(path);
+        let start = match fs::read_to_string(dir.path.join(START_FILE)) {
+            Ok(start) => Some(start.trim().parse().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupt start offset: {e}"),
+                )
+            })?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e),
+        };
+        let mut recovered: Vec<Segment> = Vec::new();
+        let mut next = 0;
+        let segments = dir.segments()?;
+        for (i, (base_offset, file)) in segments.iter().enumerate() {
+            let mut bytes = Vec::new();
+            File::open(file)?.read_to_end(&mut bytes)?;
+            let ordered = *base_offset >= next;
+            let mut entries = Vec::new();
+            if ordered {
+                next = *base_offset;
+                for chunk in bytes.chunks_exact(RECORD_SIZE) {
+                    match decode(chunk) {
+                        Some(entry) if entry.offset >= next => entries.push(entry),
+                        _ => break,
+                    }
+                    next = entries.last().expect("we just pushed").offset + 1;
+                }
+            }
+            let valid = entries.len();
+            if ordered {
+                recovered.push((*base_offset, entries));
+            }
+            if ordered && valid * RECORD_SIZE == bytes.len() {
+                continue;
+            }
+            if valid == 0 {
+                fs::remove_file(file)?;
+            } else {
+                OpenOptions::new()
+                    .write(true)
+                    .open(file)?
+                    .set_len((valid * RECORD_SIZE) as u64)?;
+            }
+            for (_, later) in &segments[i + 1..] {
+                fs::remove_file(later)?;
+            }
+            break;
+        }
+        recovered.retain(|(_, entries)| !entries.is_empty());
+        Ok((dir, recovered, start))
+    }
+
+    /// Append `entry` to the file of the segment that starts at `base_offset`.
+    pub fn append(&mut self, base_offset: u64, entry: LogEntry) -> io::Result<()> {
+        let path = self.segment_path(base_offset);
+        let file = match &mut self.active {
+            Some((base, file)) if *base == base_offset => file,
+            active => {
+                // A new segment: the previous one is sealed, so it's worth making it durable.
+                if let Some((_, file)) = active.take() {
+                    file.sync_data()?;
+                }
+                fs::create_dir_all(&self.path)?;
+                let file = OpenOptions::new().create(true).append(true).open(path)?;
+                &mut active.insert((base_offset, file)).1
+            }
+        };
+        file.write_all(&encode(&entry))?;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Read the messages of the segment that starts at `base_offset` back from its file.
+    pub fn read(&self, base_offset: u64) -> io::Result<Vec<LogEntry>> {
+        let bytes = fs::read(self.segment_path(base_offset))?;
+        bytes
+            .chunks_exact(RECORD_SIZE)
+            .map(|chunk| {
+                decode(chunk).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("corrupt record in segment {base_offset}"),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Make sure that everything written so far survives a crash.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if let (true, Some((_, file))) = (self.dirty, &self.active) {
+            file.sync_data()?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    /// Keep the first `len` records of the segment starting at `base_offset` and drop all later segments,
+    /// or drop all segments if `keep` is `None`.
+    pub fn truncate(&mut self, keep: Option<(u64, usize)>) -> io::Result<()> {
+        for (base_offset, file) in self.segments()? {
+            match keep {
+                Some((base, len)) if base_offset == base => {
+                    OpenOptions::new()
+                        .write(true)
+                        .open(file)?
+                        .set_len((len * RECORD_SIZE) as u64)?;
+                }
+                Some((base, _)) if base_offset < base => {}
+                _ => {
+                    self.close(base_offset);
+                    fs::remove_file(file)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop all segments, and continue at offset `start` from now on, also after a restart.
+    pub fn reset(&mut self, start: u64) -> io::Result<()> {
+        self.truncate(None)?;
+        fs::create_dir_all(&self.path)?;
+        write_atomically(
+            &self.path.join(START_FILE),
+            start.to_string().as_bytes(),
+            true,
+        )
+    }
+
+    /// Replace the file of the segment that starts at `base_offset` with one holding only `entries`.
+    pub fn rewrite(&mut self, base_offset: u64, entries: &[LogEntry]) -> io::Result<()> {
+        self.close(base_offset);
+        let contents: Vec<u8> = entries.iter().flat_map(encode).collect();
+        write_atomically(&self.segment_path(base_offset), &contents, true)
+    }
+
+    /// Delete the file of the segment that starts at `base_offset`.
+    pub fn remove(&mut self, base_offset: u64) -> io::Result<()> {
+        self.close(base_offset);
+        fs::remove_file(self.segment_path(base_offset))
+    }
+
+    /// Stop appending to the segment that starts at `base_offset`, if we are.
+    fn close(&mut self, base_offset: u64) {
+        if self.active.as_ref().is_some_and(|(b, _)| *b == base_offset) {
+            self.active = None;
+        }
+    }
+
+    fn segment_path(&self, base_offset: u64) -> PathBuf {
+        self.path.join(format!("{base_offset:020}.log"))
+    }
+
+    /// The segment files, ordered by base offset.
+    fn segments(&self) -> io::Result<Vec<(u64, PathBuf)>> {
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut segments = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            let base_offset = path
+                .file_name()
+                .and_then(|name| name.to_str()?.strip_suffix(".log")?.parse().ok());
+            if let Some(base_offset) = base_offset {
+                segments.push((base_offset, path));
+            }
+        }
+        segments.sort();
+        Ok(segments)
+    }
+}
+
+/// The name of the directory in which the log of `key` is kept, which is safe whatever the key contains.
+/// The prefix keeps it apart from the other files of the node, and from the node's directory itself
+/// if the key is empty.
+pub(super) fn key_dir_name(key: &str) -> String {
+    key.bytes().fold(String::from("k-"), |mut name, b| {
+        let _ = write!(name, "{b:02x}");
+        name
+    })
+}
+
+/// The inverse of [`key_dir_name`].
+pub(super) fn key_from_dir_name(name: &str) -> Option<String> {
+    let name = name.strip_prefix("k-")?;
+    let bytes = (0..name.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(name.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Write `contents` to `path` atomically, so that a crash leaves either the old or the new version.
+pub(super) fn write_atomically(path: &Path, contents: &[u8], sync: bool) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    if sync {
+        file.sync_data()?;
+    }
+    fs::rename(tmp, path)
+}
@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// The members of a consumer group and which keys each of them consumes, as seen by its coordinator.
+///
+/// Members join with their first heartbeat and leave explicitly or by missing heartbeats for
+/// a whole session timeout. Every change of members or of the keys they subscribe to starts a new
+/// generation, in which the keys are assigned anew. Members learn about it with their next heartbeat.
+///
+/// All of this is kept in memory only: if the coordinator restarts, the members simply join again.
+#[derive(Debug, Default)]
+pub(super) struct Group {
+    generation: u64,
+    members: BTreeMap<String, Member>,
+    /// The keys that each member consumes in the current generation.
+    assignment: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug)]
+struct Member {
+    /// The keys that the member wants to consume.
+    subscription: BTreeSet<String>,
+    last_heartbeat: Instant,
+}
+
+impl Group {
+    /// Record a heartbeat of `member`, which joins if it isn't a member yet,
+    /// and return the current generation with the keys assigned to the member.
+    pub fn heartbeat(
+        &mut self,
+        member: &str,
+        subscription: BTreeSet<String>,
+        session_timeout: Duration,
+    ) -> (u64, Vec<String>) {
+        let now = Instant::now();
+        let mut changed = self.expire(now, session_timeout);
+        match self.members.get_mut(member) {
+            Some(known) => {
+                known.last_heartbeat = now;
+                if known.subscription != subscription {
+                    known.subscription = subscription;
+                    changed = true;
+                }
+            }
+            None => {
+                let joined = Member {
+                    subscription,
+                    last_heartbeat: now,
+                };
+                self.members.insert(member.to_owned(), joined);
+                changed = true;
+            }
+        }
+        if changed {
+            self.rebalance();
+        }
+        let assigned = self.assignment.get(member).cloned().unwrap_or_default();
+        (self.generation, assigned)
+    }
+
+    /// Remove `member` from the group right away, rather than after its session times out.
+    pub fn leave(&mut self, member: &str) {
+        if self.members.remove(member).is_some() {
+            self.rebalance();
+        }
+    }
+
+    /// Remove the members that haven't sent a heartbeat within the session timeout,
+    /// and return whether there were any.
+    fn expire(&mut self, now: Instant, session_timeout: Duration) -> bool {
+        let before = self.members.len();
+        self.members
+            .retain(|_, member| now.duration_since(member.last_heartbeat) < session_timeout);
+        self.members.len() < before
+    }
+
+    /// Start a new generation, in which every subscribed key goes to the subscriber
+    /// that has the fewest keys so far.
+    fn rebalance(&mut self) {
+        self.generation += 1;
+        self.assignment = self
+            .members
+            .keys()
+            .map(|member| (member.clone(), Vec::new()))
+            .collect();
+        let keys: BTreeSet<&String> = self
+            .members
+            .values()
+            .flat_map(|member| &member.subscription)
+            .collect();
+        for key in keys {
+            let assignee = self
+                .members
+                .iter()
+                .filter(|(_, member)| member.subscription.contains(key))
+                .map(|(id, _)| id)
+                .min_by_key(|&id| self.assignment[id].len())
+                .expect("someone subscribed to the key");
+            self.assignment
+                .get_mut(assignee)
+                .expect("every member has an assignment")
+                .push(key.clone());
+        }
+    }
+}
@@ -0,0 +1,646 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::Duration;
+use std::{io, slice};
+
+use tokio::time::Instant;
+
+use super::disk::LogDir;
+use super::{LogEntry, Marker};
+
+/// The number of messages after which a segment is sealed and a new one is started.
+const SEGMENT_SIZE: usize = 1024;
+
+/// Every how many messages a segment adds an entry to its offset index.
+const INDEX_INTERVAL: usize = 32;
+
+/// How many sealed segments of a log on disk stay in memory. Older ones are read back from disk
+/// whenever they're needed, which is rare: consumers and followers mostly read the latest messages.
+const RESIDENT_SEGMENTS: usize = 2;
+
+/// How many of the latest sends of each idempotent producer a log remembers.
+const PRODUCER_WINDOW: usize = 5;
+
+/// What a log makes of a send with a producer id and sequence number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Sequence {
+    /// A send that's not in the log yet.
+    New,
+    /// A send that's already in the log, at this offset.
+    Duplicate(u64),
+    /// A send that's older than the ones we remember, so we can't tell.
+    Stale,
+}
+
+/// How far a transaction has got in a log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum TxnState {
+    /// The log has no messages of the transaction (anymore).
+    Unknown,
+    /// The log has messages of the transaction, but no marker yet.
+    Open,
+    Ended(Marker),
+}
+
+/// The messages of a single key, in offset order.
+///
+/// Like Kafka's logs, it's split into segments, of which only the last one is appended to.
+/// Every segment has a sparse index of offsets, so finding an offset means two binary searches
+/// and a scan of at most [`INDEX_INTERVAL`] messages, however long the log is.
+/// Old segments are dropped whole by retention (see [`Log::drop_before`]) and thinned out
+/// by compaction, so the offsets in the log can have gaps, but they never change.
+///
+/// Logs are kept in memory, and optionally also written to disk, with a file per segment.
+/// Logs on disk only keep their latest [`RESIDENT_SEGMENTS`] sealed segments in memory,
+/// so their memory use is bounded however long they get. Logs that are only in memory
+/// are bounded by retention.
+#[derive(Debug, Default)]
+pub(super) struct Log {
+    next_offset: u64,
+    segments: Vec<Segment>,
+    /// The leader epochs in which the messages were appended, as `(epoch, first offset)`.
+    /// Two replicas whose logs hold a message of the same epoch at the same offset agree
+    /// on everything up to that offset, since only one leader appends in each epoch.
+    epochs: Vec<(u64, u64)>,
+    /// The latest `(sequence number, offset)` of every idempotent producer, oldest first.
+    /// Rebuilt from the messages when the log is opened or truncated, and producers are
+    /// forgotten once retention has deleted all their messages (see [`Log::drop_before`]).
+    producers: HashMap<u64, VecDeque<(u64, u64)>>,
+    /// The transactions with messages but no marker in the log, with the offset of their first message.
+    open: HashMap<u64, u64>,
+    /// The transactions whose marker is in the log, and how they ended.
+    ended: HashMap<u64, Marker>,
+    /// `(transaction, offset of its first message)` by the offset of its marker, for the transactions
+    /// that had messages in the log when they ended.
+    markers: BTreeMap<u64, (u64, u64)>,
+    dir: Option<LogDir>,
+}
+
+#[derive(Debug)]
+struct Segment {
+    /// The offset of the first message that was appended to the segment.
+    base_offset: u64,
+    /// The messages, or `None` if the segment was evicted from memory and is only on disk.
+    entries: Option<Vec<LogEntry>>,
+    /// The number of messages.
+    len: usize,
+    /// The offset after the last message, or the base offset if there is none.
+    end: u64,
+    /// `(offset, position in entries)` of every `INDEX_INTERVAL`th message, while in memory.
+    index: Vec<(u64, usize)>,
+    /// Sealed segments aren't appended to anymore.
+    sealed: bool,
+    /// When the last message was appended, for time-based retention.
+    last_append: Instant,
+}
+
+impl Segment {
+    fn new(base_offset: u64) -> Self {
+        Self {
+            base_offset,
+            entries: Some(Vec::with_capacity(SEGMENT_SIZE)),
+            len: 0,
+            end: base_offset,
+            index: Vec::new(),
+            sealed: false,
+            last_append: Instant::now(),
+        }
+    }
+
+    fn append(&mut self, entry: LogEntry) {
+        let entries = self
+            .entries
+            .as_mut()
+            .expect("the active segment is in memory");
+        if entries.len() % INDEX_INTERVAL == 0 {
+            self.index.push((entry.offset, entries.len()));
+        }
+        entries.push(entry);
+        self.len = entries.len();
+        self.end = entry.offset + 1;
+        self.last_append = Instant::now();
+    }
+
+    fn seal(&mut self) {
+        self.sealed = true;
+        if let Some(entries) = &mut self.entries {
+            entries.shrink_to_fit();
+        }
+        self.index.shrink_to_fit();
+    }
+
+    /// Drop the messages from memory. They have to be on disk.
+    fn evict(&mut self) {
+        self.entries = None;
+        self.index = Vec::new();
+    }
+
+    /// Read the messages back from `dir` if the segment was evicted.
+    fn load(&mut self, dir: Option<&LogDir>) -> io::Result<()> {
+        if self.entries.is_none() {
+            let dir = dir.expect("only segments on disk are evicted");
+            self.entries = Some(dir.read(self.base_offset)?);
+            self.reindex();
+        }
+        Ok(())
+    }
+
+    /// Rebuild the index, the length and the end after the messages were changed.
+    fn reindex(&mut self) {
+        let entries = self.entries.as_deref().unwrap_or_default();
+        self.index = (0..entries.len())
+            .step_by(INDEX_INTERVAL)
+            .map(|position| (entries[position].offset, position))
+            .collect();
+        self.len = entries.len();
+        self.end = entries
+            .last()
+            .map_or(self.base_offset, |entry| entry.offset + 1);
+    }
+}
+
+/// The position in `entries` of the first message at or after `offset`,
+/// using the `index` of their segment if it has one.
+fn seek(entries: &[LogEntry], index: &[(u64, usize)], offset: u64) -> usize {
+    let i = index.partition_point(|&(indexed, _)| indexed <= offset);
+    let start = i.checked_sub(1).map_or(0, |i| index[i].1);
+    start
+        + entries[start..]
+            .iter()
+            .take_while(|entry| entry.offset < offset)
+            .count()
+}
+
+/// The messages of a [`Log`] from some offset on, read back from disk where they aren't in memory.
+pub(super) struct Entries<'a> {
+    log: &'a Log,
+    from: u64,
+    segments: slice::Iter<'a, Segment>,
+    /// The messages of the current segment, and the position of the next one.
+    current: Cow<'a, [LogEntry]>,
+    position: usize,
+}
+
+impl Iterator for Entries<'_> {
+    type Item = io::Result<LogEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.position == self.current.len() {
+            let segment = self.segments.next()?;
+            let entries = match (&segment.entries, &self.log.dir) {
+                (Some(entries), _) => Cow::Borrowed(entries.as_slice()),
+                (None, Some(dir)) => match dir.read(segment.base_offset) {
+                    Ok(entries) => Cow::Owned(entries),
+                    Err(e) => {
+                        self.segments = [].iter();
+                        return Some(Err(e));
+                    }
+                },
+                (None, None) => unreachable!("only segments on disk are evicted"),
+            };
+            self.position = seek(&entries, &segment.index, self.from);
+            self.current = entries;
+        }
+        let entry = self.current[self.position];
+        self.position += 1;
+        Some(Ok(entry))
+    }
+}
+
+impl Log {
+    /// The offset that the next message will get, which is also the length of the log.
+    pub fn len(&self) -> u64 {
+        self.next_offset
+    }
+
+    /// The low-water mark: everything before it has been deleted.
+    pub fn start_offset(&self) -> u64 {
+        self.segments
+            .first()
+            .map_or(self.next_offset, |segment| segment.base_offset)
+    }
+
+    /// An empty log, which is written to `path` on disk if there is one.
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            dir: path.map(LogDir::new),
+            ..Self::default()
+        }
+    }
+
+    /// Read the log that was written to `path` before, e.g. by a previous run of this node.
+    pub fn open(path: PathBuf) -> io::Result<Self> {
+        let (dir, segments, start) = LogDir::recover(path)?;
+        let mut log = Self {
+            next_offset: start.unwrap_or(0),
+            ..Self::default()
+        };
+        let count = segments.len();
+        for (i, (base_offset, entries)) in segments.into_iter().enumerate() {
+            let mut segment = Segment::new(base_offset);
+            for entry in entries {
+                log.record_epoch(entry.epoch, entry.offset);
+                log.record_producer(&entry);
+                log.record_txn(&entry);
+                segment.append(entry);
+            }
+            // Only the last segment can still have room, unless it was compacted.
+            if i + 1 < count || segment.len >= SEGMENT_SIZE {
+                segment.seal();
+            }
+            log.next_offset = segment.end;
+            log.segments.push(segment);
+        }
+        log.dir = Some(dir);
+        log.evict();
+        Ok(log)
+    }
+
+    pub fn append(
+        &mut self,
+        msg: u64,
+        msg_key: Option<u64>,
+        producer: Option<(u64, u64)>,
+        epoch: u64,
+    ) -> io::Result<u64> {
+        let offset = self.next_offset;
+        self.append_entry(LogEntry {
+            offset,
+            epoch,
+            msg,
+            msg_key,
+            producer,
+            txn: None,
+            marker: None,
+        })?;
+        Ok(offset)
+    }
+
+    /// Append `msg` as part of the transaction `txn`, or the marker that ends it if `marker` is set.
+    pub fn append_txn(
+        &mut self,
+        txn: u64,
+        msg: u64,
+        marker: Option<Marker>,
+        epoch: u64,
+    ) -> io::Result<u64> {
+        let offset = self.next_offset;
+        self.append_entry(LogEntry {
+            offset,
+            epoch,
+            msg,
+            msg_key: None,
+            producer: None,
+            txn: Some(txn),
+            marker,
+        })?;
+        Ok(offset)
+    }
+
+    /// Append a message that was copied from another log. Its offset can be beyond the end
+    /// of this one, if the messages in between were compacted away.
+    pub fn append_entry(&mut self, entry: LogEntry) -> io::Result<()> {
+        debug_assert!(entry.offset >= self.next_offset);
+        self.record_epoch(entry.epoch, entry.offset);
+        self.record_producer(&entry);
+        self.record_txn(&entry);
+        if self.segments.last().map_or(true, |segment| segment.sealed) {
+            self.segments.push(Segment::new(entry.offset));
+        }
+        let active = self
+            .segments
+            .last_mut()
+            .expect("there is an active segment");
+        active.append(entry);
+        let base_offset = active.base_offset;
+        let sealed = active.len >= SEGMENT_SIZE;
+        if sealed {
+            active.seal();
+        }
+        self.next_offset = entry.offset + 1;
+        if let Some(dir) = &mut self.dir {
+            dir.append(base_offset, entry)?;
+        }
+        if sealed {
+            self.evict();
+        }
+        Ok(())
+    }
+
+    /// Drop all but the latest [`RESIDENT_SEGMENTS`] sealed segments from memory,
+    /// if the log is on disk.
+    fn evict(&mut self) {
+        if self.dir.is_none() {
+            return;
+        }
+        let sealed = self
+            .segments
+            .iter_mut()
+            .rev()
+            .filter(|segment| segment.sealed);
+        for segment in sealed.skip(RESIDENT_SEGMENTS) {
+            segment.evict();
+        }
+    }
+
+    fn record_epoch(&mut self, epoch: u64, offset: u64) {
+        if self.epochs.last().map_or(true, |&(last, _)| last != epoch) {
+            self.epochs.push((epoch, offset));
+        }
+    }
+
+    fn record_producer(&mut self, entry: &LogEntry) {
+        if let Some((id, seq)) = entry.producer {
+            let latest = self.producers.entry(id).or_default();
+            latest.push_back((seq, entry.offset));
+            if latest.len() > PRODUCER_WINDOW {
+                latest.pop_front();
+            }
+        }
+    }
+
+    fn record_txn(&mut self, entry: &LogEntry) {
+        let Some(txn) = entry.txn else {
+            return;
+        };
+        match entry.marker {
+            None if !self.ended.contains_key(&txn) => {
+                self.open.entry(txn).or_insert(entry.offset);
+            }
+            None => {}
+            Some(marker) => {
+                if let Some(first) = self.open.remove(&txn) {
+                    self.markers.insert(entry.offset, (txn, first));
+                }
+                self.ended.insert(txn, marker);
+            }
+        }
+    }
+
+    /// Forget about the producers and transactions and find them again in the messages that are left.
+    fn rebuild_state(&mut self) -> io::Result<()> {
+        let mut relevant = Vec::new();
+        for entry in self.entries(0) {
+            let entry = entry?;
+            if entry.producer.is_some() || entry.txn.is_some() {
+                relevant.push(entry);
+            }
+        }
+        self.producers.clear();
+        self.open.clear();
+        self.ended.clear();
+        self.markers.clear();
+        for entry in &relevant {
+            self.record_producer(entry);
+            self.record_txn(entry);
+        }
+        Ok(())
+    }
+
+    pub fn txn_state(&self, txn: u64) -> TxnState {
+        match self.ended.get(&txn) {
+            Some(&marker) => TxnState::Ended(marker),
+            None if self.open.contains_key(&txn) => TxnState::Open,
+            None => TxnState::Unknown,
+        }
+    }
+
+    /// The transactions that have messages but no marker in the log.
+    pub fn open_txns(&self) -> impl Iterator<Item = u64> + '_ {
+        self.open.keys().copied()
+    }
+
+    /// The offset before which no transaction is still undecided, as far as the messages
+    /// before `until` go: the first message of the earliest transaction that is open,
+    /// or whose marker is not before `until`. Consumers can't read past it, since they'd
+    /// have to skip messages that might turn out to be committed.
+    pub fn stable_offset(&self, until: u64) -> u64 {
+        let ending = self.markers.range(until..).map(|(_, &(_, first))| first);
+        self.open
+            .values()
+            .copied()
+            .chain(ending)
+            .fold(until, u64::min)
+    }
+
+    /// Whether consumers get to see `entry`, if it's before the stable offset.
+    fn is_visible(&self, entry: &LogEntry) -> bool {
+        entry.marker.is_none()
+            && entry
+                .txn
+                .map_or(true, |txn| self.ended.get(&txn) != Some(&Marker::Abort))
+    }
+
+    /// Whether the send with sequence number `seq` of producer `id` is in the log already.
+    /// Sequence numbers only ever increase, so any later one is new.
+    pub fn sequence(&self, id: u64, seq: u64) -> Sequence {
+        let Some(latest) = self.producers.get(&id) else {
+            return Sequence::New;
+        };
+        if let Some(&(_, offset)) = latest.iter().find(|&&(known, _)| known == seq) {
+            return Sequence::Duplicate(offset);
+        }
+        match latest.back() {
+            Some(&(last, _)) if seq < last => Sequence::Stale,
+            _ => Sequence::New,
+        }
+    }
+
+    /// Make sure that all appended messages survive a crash, if the log is on disk.
+    pub fn sync(&mut self) -> io::Result<()> {
+        match &mut self.dir {
+            Some(dir) => dir.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// The epoch in which the message at `offset` was appended, or `None` if there is none yet.
+    /// Deleted and compacted messages keep their epoch.
+    pub fn epoch_at(&self, offset: u64) -> Option<u64> {
+        if offset >= self.next_offset {
+            return None;
+        }
+        let i = self.epochs.partition_point(|&(_, start)| start <= offset);
+        Some(self.epochs.get(i.checked_sub(1)?)?.0)
+    }
+
+    /// Up to `limit` messages from offset `from` on, but not from `until` or the stable offset on.
+    /// Transaction markers and the messages of aborted transactions are skipped.
+    pub fn read(&self, from: u64, until: u64, limit: usize) -> io::Result<Vec<(u64, u64)>> {
+        let until = self.stable_offset(until);
+        let mut msgs = Vec::new();
+        for entry in self.entries(from) {
+            let entry = entry?;
+            if entry.offset >= until || msgs.len() >= limit {
+                break;
+            }
+            if self.is_visible(&entry) {
+                msgs.push((entry.offset, entry.msg));
+            }
+        }
+        Ok(msgs)
+    }
+
+    /// The messages from offset `from` on, skipping whatever was deleted or compacted.
+    pub fn entries(&self, from: u64) -> Entries<'_> {
+        let first = self
+            .segments
+            .partition_point(|segment| segment.base_offset <= from)
+            .saturating_sub(1);
+        Entries {
+            log: self,
+            from,
+            segments: self.segments[first..].iter(),
+            current: Cow::Borrowed(&[]),
+            position: 0,
+        }
+    }
+
+    /// Drop all messages from offset `len` on.
+    pub fn truncate(&mut self, len: u64) -> io::Result<()> {
+        let keep = self
+            .segments
+            .partition_point(|segment| segment.base_offset < len);
+        self.segments.truncate(keep);
+        let mut kept = None;
+        if let Some(segment) = self.segments.last_mut() {
+            segment.load(self.dir.as_ref())?;
+            let entries = segment
+                .entries
+                .as_mut()
+                .expect("the segment was just loaded");
+            let position = seek(entries, &segment.index, len);
+            entries.truncate(position);
+            segment.reindex();
+            // The last segment is the active one again, unless it's still full.
+            segment.sealed = segment.len >= SEGMENT_SIZE;
+            kept = Some((segment.base_offset, position));
+        }
+        self.epochs.retain(|(_, start)| *start < len);
+        self.next_offset = self.next_offset.min(len);
+        self.rebuild_state()?;
+        match (&mut self.dir, kept) {
+            (Some(dir), Some(_)) => dir.truncate(kept)?,
+            // With no messages left, only the start file knows where the log continues.
+            (Some(dir), None) => dir.reset(self.next_offset)?,
+            (None, _) => {}
+        }
+        self.evict();
+        Ok(())
+    }
+
+    /// Drop all messages and continue at offset `start`, for a replica that's missing
+    /// messages that have already been deleted everywhere else.
+    pub fn reset(&mut self, start: u64) -> io::Result<()> {
+        self.segments.clear();
+        self.epochs.clear();
+        self.next_offset = start;
+        self.rebuild_state()?;
+        match &mut self.dir {
+            Some(dir) => dir.reset(start),
+            None => Ok(()),
+        }
+    }
+
+    /// Delete the oldest segments that end at or before `limit`, as long as their last message
+    /// is older than `max_age` or there are at least `max_messages` offsets after them.
+    ///
+    /// The last segment is always kept, so that the log still knows where it ends.
+    pub fn apply_retention(
+        &mut self,
+        max_age: Option<Duration>,
+        max_messages: Option<u64>,
+        limit: u64,
+    ) -> io::Result<()> {
+        let mut start = self.start_offset();
+        for (segment, next) in self.segments.iter().zip(self.segments.iter().skip(1)) {
+            let end = next.base_offset;
+            let too_old = max_age.is_some_and(|age| segment.last_append.elapsed() >= age);
+            let too_many = max_messages.is_some_and(|max| self.next_offset - end >= max);
+            if end > limit || !(too_old || too_many) {
+                break;
+            }
+            start = end;
+        }
+        self.drop_before(start)
+    }
+
+    /// Drop the segments that only have messages before `offset`, in memory and on disk.
+    ///
+    /// The last segment is always kept, so that the log still knows where it ends.
+    pub fn drop_before(&mut self, offset: u64) -> io::Result<()> {
+        let dropped = self
+            .segments
+            .windows(2)
+            .take_while(|pair| pair[1].base_offset <= offset)
+            .count();
+        for segment in self.segments.drain(..dropped) {
+            if let Some(dir) = &mut self.dir {
+                dir.remove(segment.base_offset)?;
+            }
+        }
+        // Transactions are only forgotten along with their marker, after all their messages.
+        let start = self.start_offset();
+        let kept = self.markers.split_off(&start);
+        for (txn, _) in std::mem::replace(&mut self.markers, kept).into_values() {
+            self.ended.remove(&txn);
+        }
+        self.producers
+            .retain(|_, latest| latest.back().is_some_and(|&(_, offset)| offset >= start));
+        Ok(())
+    }
+
+    /// Remove the messages from the sealed segments before `until` that have a later message
+    /// with the same key before `until`. Messages without a key are always kept.
+    ///
+    /// The last segment is never compacted, so the last message of the log survives.
+    /// Segments that end up empty are dropped altogether.
+    pub fn compact(&mut self, until: u64) -> io::Result<()> {
+        let mut latest = HashMap::new();
+        for entry in self.entries(0) {
+            let entry = entry?;
+            if entry.offset >= until {
+                break;
+            }
+            if let Some(msg_key) = entry.msg_key {
+                latest.insert(msg_key, entry.offset);
+            }
+        }
+        let last = self.segments.len().saturating_sub(1);
+        for segment in &mut self.segments[..last] {
+            if !segment.sealed || segment.end > until {
+                break;
+            }
+            // Evicted segments are only loaded one at a time, and go again afterwards.
+            let evicted = segment.entries.is_none();
+            segment.load(self.dir.as_ref())?;
+            let entries = segment
+                .entries
+                .as_mut()
+                .expect("the segment was just loaded");
+            let before = entries.len();
+            entries.retain(|entry| {
+                entry
+                    .msg_key
+                    .map_or(true, |msg_key| latest[&msg_key] == entry.offset)
+            });
+            if entries.len() != before {
+                if let Some(dir) = &mut self.dir {
+                    if entries.is_empty() {
+                        dir.remove(segment.base_offset)?;
+                    } else {
+                        dir.rewrite(segment.base_offset, entries)?;
+                    }
+                }
+                segment.reindex();
+            }
+            if evicted {
+                segment.evict();
+            }
+        }
+        self.segments
+            .retain(|segment| !(segment.sealed && segment.len == 0));
+        Ok(())
+    }
+}
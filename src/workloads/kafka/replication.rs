@@ -0,0 +1,483 @@
+use std::collections::BTreeMap;
+use std::rc::Rc;
+
+use futures::future::join_all;
+use serde::{Deserialize, Serialize};
+use tokio::task;
+
+use super::{io_error, FsyncPolicy, Kafka, KafkaBody, LogEntry, REPLICATION_BATCH};
+use crate::{Error, ErrorCode, Node};
+
+/// Who leads a key and which of its replicas are in sync with the leader, as stored in lin-kv.
+///
+/// Every change of leader bumps the epoch, and replicas reject messages from the leaders of
+/// earlier epochs. All changes are a CAS on the previous value, so a deposed leader can neither
+/// replicate to the new one nor drop it from the ISR to carry on without it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub(super) struct Leadership {
+    pub leader: String,
+    pub epoch: u64,
+    /// The in-sync replicas, including the leader. Every message below the high-water mark
+    /// is on all of them, so any of them can take over without losing acknowledged messages.
+    pub isr: Vec<String>,
+}
+
+/// The lin-kv key under which the leadership of `key` is stored.
+fn leadership_key(key: &str) -> String {
+    format!("leader/{key}")
+}
+
+fn not_leader(key: &str) -> Error {
+    Error::new(
+        ErrorCode::TemporarilyUnavailable,
+        format!("not the leader of {key}"),
+    )
+}
+
+impl Kafka {
+    /// Who currently leads `key`, as far as we know.
+    ///
+    /// The first node to look up a key puts its owner in charge, with all replicas in sync.
+    pub(super) async fn leadership(&self, node: &Node, key: &str) -> Result<Leadership, Error> {
+        let cached = self
+            .partitions
+            .lock()
+            .await
+            .get(key)
+            .and_then(|p| p.leadership.clone());
+        if let Some(leadership) = cached {
+            return Ok(leadership);
+        }
+        let replicas = self.replicas(node, key);
+        let initial = Leadership {
+            leader: replicas[0].clone(),
+            epoch: 0,
+            isr: replicas,
+        };
+        let leadership = if self.config.replication <= 1 {
+            // Without followers the owner is the only one who can lead, so there's nothing to agree on.
+            initial
+        } else {
+            match self.kv.read(node, leadership_key(key)).await {
+                Err(Error {
+                    code: ErrorCode::KeyDoesNotExist,
+                    ..
+                }) => {
+                    let create = self
+                        .kv
+                        .cas_or_create(node, leadership_key(key), &initial, &initial)
+                        .await;
+                    match create {
+                        Ok(()) => initial,
+                        Err(Error {
+                            code: ErrorCode::PreconditionFailed,
+                            ..
+                        }) => self.kv.read(node, leadership_key(key)).await?,
+                        Err(e) => return Err(e),
+                    }
+                }
+                result => result?,
+            }
+        };
+        self.learn(key, &leadership).await;
+        Ok(leadership)
+    }
+
+    /// Remember `leadership`, unless we already know of a later epoch.
+    async fn learn(&self, key: &str, leadership: &Leadership) {
+        let mut partitions = self.partitions.lock().await;
+        let partition = self.partition(&mut partitions, key);
+        if leadership.epoch < partition.epoch {
+            return;
+        }
+        if leadership.epoch > partition.epoch {
+            partition.epoch = leadership.epoch;
+            partition.progress.clear();
+        }
+        partition.leadership = Some(leadership.clone());
+    }
+
+    /// Drop our view of who leads `key`, so that the next lookup goes to lin-kv.
+    pub(super) async fn forget(&self, key: &str) {
+        if let Some(partition) = self.partitions.lock().await.get_mut(key) {
+            partition.leadership = None;
+        }
+    }
+
+    /// Replace the leadership of `key` with `to`, if it's still `from`.
+    /// Returns whether it was, and forgets about the leadership if not.
+    async fn update_leadership(
+        &self,
+        node: &Node,
+        key: &str,
+        from: &Leadership,
+        to: Leadership,
+    ) -> Result<bool, Error> {
+        match self.kv.cas(node, leadership_key(key), from, &to).await {
+            Ok(()) => {
+                self.learn(key, &to).await;
+                Ok(true)
+            }
+            Err(Error {
+                code: ErrorCode::PreconditionFailed,
+                ..
+            }) => {
+                self.forget(key).await;
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Bring the in-sync replicas of `key` up to date with our log, and move the high-water mark
+    /// up to its current end. Followers that don't respond in time are dropped from the ISR,
+    /// and out-of-sync replicas are caught up in the background. Replicas that are joining the ISR
+    /// are brought up to date as well, but we wait for them instead of dropping them.
+    ///
+    /// Fails if it turns out that we don't lead `key` (anymore).
+    pub(super) async fn replicate(
+        self: &Rc<Self>,
+        node: &Rc<Node>,
+        key: &str,
+    ) -> Result<(), Error> {
+        // Read the end of the log before the leadership: a replica that joins the ISR in between
+        // already has everything up to the end at the time it joins.
+        let end = self
+            .partitions
+            .lock()
+            .await
+            .get(key)
+            .map_or(0, |p| p.log.len());
+        loop {
+            let leadership = self.leadership(node, key).await?;
+            if leadership.leader != node.id() {
+                return Err(not_leader(key));
+            }
+            for replica in self.replicas(node, key) {
+                if !leadership.isr.contains(&replica) {
+                    self.catch_up(node, key, replica).await;
+                }
+            }
+
+            // Read who is joining after the end of the log: a replica that starts joining
+            // after that already had everything up to the end.
+            let joining = self
+                .partitions
+                .lock()
+                .await
+                .get(key)
+                .map(|p| p.joining.clone())
+                .unwrap_or_default();
+            let followers: Vec<&String> = leadership
+                .isr
+                .iter()
+                .chain(
+                    joining
+                        .iter()
+                        .filter(|&replica| !leadership.isr.contains(replica)),
+                )
+                .filter(|&replica| replica != node.id())
+                .collect();
+            let results = join_all(
+                followers
+                    .iter()
+                    .map(|follower| self.replicate_to(node, key, follower, leadership.epoch, end)),
+            )
+            .await;
+            let mut isr = leadership.isr.clone();
+            let mut joined = true;
+            for (follower, result) in followers.into_iter().zip(results) {
+                match result {
+                    Ok(()) => {}
+                    // The follower has seen a later epoch.
+                    Err(Error {
+                        code: ErrorCode::PreconditionFailed,
+                        ..
+                    }) => {
+                        self.forget(key).await;
+                        return Err(not_leader(key));
+                    }
+                    Err(_) if isr.contains(follower) => isr.retain(|replica| replica != follower),
+                    // It might already be in the ISR, so we can't go on without it until we know.
+                    Err(_) => joined = false,
+                }
+            }
+            if isr.len() < leadership.isr.len() {
+                let shrunk = Leadership {
+                    isr,
+                    ..leadership.clone()
+                };
+                // If the CAS fails, someone else changed the leadership in the meantime:
+                // either another replication shrank or grew the ISR, and we try again,
+                // or another node took over, which the lookup at the start will tell us.
+                if !self
+                    .update_leadership(node, key, &leadership, shrunk)
+                    .await?
+                {
+                    continue;
+                }
+            }
+            if !joined {
+                continue;
+            }
+
+            let mut partitions = self.partitions.lock().await;
+            let partition = self.partition(&mut partitions, key);
+            partition.hw = partition.hw.max(end);
+            self.advanced.notify_waiters();
+            return Ok(());
+        }
+    }
+
+    /// Send `follower` the part of the log of `key` that it's missing, until it has everything before `end`.
+    async fn replicate_to(
+        &self,
+        node: &Node,
+        key: &str,
+        follower: &str,
+        epoch: u64,
+        end: u64,
+    ) -> Result<(), Error> {
+        loop {
+            let (from, request) = {
+                let partitions = self.partitions.lock().await;
+                let Some(partition) = partitions.get(key) else {
+                    return Ok(());
+                };
+                // Until we hear otherwise, assume that followers have everything below the
+                // high-water mark. If not, they tell us where they are.
+                let from = partition
+                    .progress
+                    .get(follower)
+                    .copied()
+                    .unwrap_or(partition.hw)
+                    .min(partition.log.len())
+                    .max(partition.log.start_offset());
+                let msgs = partition
+                    .log
+                    .entries(from)
+                    .take(REPLICATION_BATCH)
+                    .collect::<Result<_, _>>()
+                    .map_err(io_error)?;
+                let request = KafkaBody::Replicate {
+                    key: key.to_owned(),
+                    epoch,
+                    start: partition.log.start_offset(),
+                    from,
+                    prev_epoch: from
+                        .checked_sub(1)
+                        .and_then(|prev| partition.log.epoch_at(prev)),
+                    msgs,
+                    hw: partition.hw,
+                    committed: partition.committed.clone(),
+                };
+                (from, request)
+            };
+            let reply = node
+                .rpc_with(follower, request, &self.replica_policy())
+                .await?;
+            let KafkaBody::ReplicateOk { next } = reply.body.inner else {
+                return Err(Error::new(
+                    ErrorCode::MalformedRequest,
+                    format!("unexpected reply from {follower}: {:?}", reply.body.inner),
+                ));
+            };
+            let mut partitions = self.partitions.lock().await;
+            let progress = self
+                .partition(&mut partitions, key)
+                .progress
+                .entry(follower.to_owned())
+                .or_insert(next);
+            if next < from {
+                // The follower's log diverges from ours before `from`, so we go back to where it's sure to agree.
+                *progress = next;
+                continue;
+            }
+            // Replies can arrive out of order, but within an epoch logs only grow.
+            *progress = (*progress).max(next);
+            if *progress >= end {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Bring `replica` up to date in the background, and add it back to the ISR of `key` once it is.
+    async fn catch_up(self: &Rc<Self>, node: &Rc<Node>, key: &str, replica: String) {
+        let mut partitions = self.partitions.lock().await;
+        let partition = self.partition(&mut partitions, key);
+        if !partition.catching_up.insert(replica.clone()) {
+            return;
+        }
+        let (kafka, node, key) = (self.clone(), node.clone(), key.to_owned());
+        task::spawn_local(async move {
+            // A replica that is still unreachable is simply tried again with the next replication.
+            let _ = kafka.rejoin(&node, &key, &replica).await;
+            if let Some(partition) = kafka.partitions.lock().await.get_mut(&key) {
+                partition.catching_up.remove(&replica);
+            }
+        });
+    }
+
+    async fn rejoin(&self, node: &Node, key: &str, replica: &str) -> Result<(), Error> {
+        let leadership = self.leadership(node, key).await?;
+        let end = self
+            .partitions
+            .lock()
+            .await
+            .get(key)
+            .map_or(0, |p| p.log.len());
+        self.replicate_to(node, key, replica, leadership.epoch, end)
+            .await?;
+
+        {
+            let mut partitions = self.partitions.lock().await;
+            let partition = self.partition(&mut partitions, key);
+            let caught_up = partition
+                .progress
+                .get(replica)
+                .is_some_and(|&progress| progress >= partition.log.len());
+            if !caught_up || partition.leadership.as_ref() != Some(&leadership) {
+                return Ok(());
+            }
+            // From here on, replications wait for the replica before they move the high-water mark,
+            // so it has every message below it by the time it's part of the ISR. The other keys
+            // carry on while we wait for lin-kv.
+            partition.joining.insert(replica.to_owned());
+        }
+        let mut grown = leadership.clone();
+        grown.isr.push(replica.to_owned());
+        let result = self
+            .kv
+            .cas(node, leadership_key(key), &leadership, &grown)
+            .await;
+        let mut partitions = self.partitions.lock().await;
+        let partition = self.partition(&mut partitions, key);
+        match result {
+            Ok(()) => partition.leadership = Some(grown),
+            Err(Error {
+                code: ErrorCode::PreconditionFailed,
+                ..
+            }) => partition.leadership = None,
+            // The replica might have joined, so it stays joining until the next attempt tells.
+            Err(e) => {
+                partition.leadership = None;
+                return Err(e);
+            }
+        }
+        partition.joining.remove(replica);
+        Ok(())
+    }
+
+    /// Take over `key` from the leader of `stale`, which didn't respond.
+    ///
+    /// Only in-sync replicas can take over, since only they are sure to have every acknowledged message.
+    /// If the leadership already changed, there's nothing to do: our next lookup finds the new leader.
+    pub(super) async fn fail_over(
+        self: &Rc<Self>,
+        node: &Rc<Node>,
+        key: &str,
+        stale: &Leadership,
+    ) -> Result<(), Error> {
+        self.forget(key).await;
+        let current = self.leadership(node, key).await?;
+        if current != *stale || !current.isr.iter().any(|replica| replica == node.id()) {
+            return Ok(());
+        }
+        let takeover = Leadership {
+            leader: node.id().to_owned(),
+            epoch: current.epoch + 1,
+            isr: current.isr.clone(),
+        };
+        if self
+            .update_leadership(node, key, &current, takeover)
+            .await?
+        {
+            // Settle the high-water mark of the new epoch, which also drops the old leader from the ISR.
+            self.replicate(node, key).await?;
+        }
+        Ok(())
+    }
+
+    /// Apply a `replicate` message from the leader of `key`,
+    /// and return the offset up to which our log now agrees with the leader's.
+    #[allow(clippy::too_many_arguments)]
+    pub(super) async fn apply_replicate(
+        &self,
+        key: &str,
+        epoch: u64,
+        start: u64,
+        from: u64,
+        prev_epoch: Option<u64>,
+        msgs: &[LogEntry],
+        hw: u64,
+        committed: &BTreeMap<String, u64>,
+    ) -> Result<u64, Error> {
+        let mut partitions = self.partitions.lock().await;
+        let partition = self.partition(&mut partitions, key);
+        if epoch < partition.epoch {
+            return Err(Error::new(
+                ErrorCode::PreconditionFailed,
+                format!(
+                    "epoch {epoch} of {key} is over, we're at {}",
+                    partition.epoch
+                ),
+            ));
+        }
+        if epoch > partition.epoch {
+            // We'll look up the new leadership when we need it.
+            partition.epoch = epoch;
+            partition.leadership = None;
+            partition.progress.clear();
+        }
+        let mut commit = false;
+        for (group, &offset) in committed {
+            if partition
+                .committed
+                .get(group)
+                .map_or(true, |&known| known < offset)
+            {
+                partition.committed.insert(group.clone(), offset);
+                commit = true;
+            }
+        }
+
+        let log = &mut partition.log;
+        if log.len() < start {
+            // The leader has already deleted what we're missing.
+            log.reset(start).map_err(io_error)?;
+        }
+        if from > log.len() {
+            return Ok(log.len());
+        }
+        // Everything below our high-water mark is on every in-sync replica, including the leader,
+        // so we only have to check that we agree on what comes after it.
+        if from > partition.hw.max(log.start_offset()) && log.epoch_at(from - 1) != prev_epoch {
+            return Ok(partition.hw.min(from - 1));
+        }
+        let mut next = from;
+        for entry in msgs {
+            // Messages might arrive again, or late, so we only throw away what disagrees
+            // with the leader: messages from earlier epochs, and messages that the leader
+            // has compacted away since. Below the high-water mark, the only difference
+            // between our logs can be what each of us has compacted.
+            let disagrees = entry.offset > next || log.epoch_at(entry.offset) != Some(entry.epoch);
+            if next < log.len() && next >= partition.hw && disagrees {
+                log.truncate(next).map_err(io_error)?;
+            }
+            if entry.offset >= log.len() {
+                log.append_entry(*entry).map_err(io_error)?;
+            }
+            next = entry.offset + 1;
+        }
+        if self.config.fsync == FsyncPolicy::Always {
+            log.sync().map_err(io_error)?;
+        }
+        // Anything after the messages we were sent might still be left over from earlier epochs,
+        // so we only vouch for what we have compared with the leader's log.
+        partition.hw = partition.hw.max(hw.min(next));
+        if commit {
+            self.checkpoint(&partitions).map_err(io_error)?;
+        }
+        Ok(next)
+    }
+}
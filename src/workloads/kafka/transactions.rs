@@ -0,0 +1,275 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::rc::Rc;
+
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+use super::{
+    indefinite, io_error, FsyncPolicy, Kafka, KafkaBody, Marker, TxnState, CLEANUP_INTERVAL,
+};
+use crate::{Error, ErrorCode, Node};
+
+/// The lin-kv key of the counter from which transactions get their ids.
+const TXN_COUNTER: &str = "txn/next";
+
+/// The lin-kv key under which the outcome of `txn` is stored.
+fn decision_key(txn: u64) -> String {
+    format!("txn/{txn}")
+}
+
+fn opposite(marker: Marker) -> Marker {
+    match marker {
+        Marker::Commit => Marker::Abort,
+        Marker::Abort => Marker::Commit,
+    }
+}
+
+impl Kafka {
+    /// Append `msgs` to their keys as a single transaction, and return their offsets.
+    ///
+    /// We coordinate the transaction with a two-phase commit: the messages are appended to the
+    /// logs of their keys first, where polls don't return them yet. Then we record in lin-kv
+    /// that the transaction is committed, and end it with a marker in each of the logs,
+    /// after which polls return its messages. If anything goes wrong before that, we end it
+    /// with abort markers instead, and polls skip its messages for good.
+    ///
+    /// Leaders abort transactions that stay open for too long in the same way, in case we crash
+    /// halfway through. Whoever records the outcome in lin-kv first wins.
+    pub(super) async fn send_txn(
+        self: &Rc<Self>,
+        node: &Rc<Node>,
+        src: &str,
+        msgs: &[(String, u64)],
+    ) -> Result<Vec<u64>, Error> {
+        if msgs.is_empty() {
+            return Ok(Vec::new());
+        }
+        let txn = self.next_txn(node).await?;
+        let keys: BTreeSet<&String> = msgs.iter().map(|(key, _)| key).collect();
+        let keys: Vec<String> = keys.into_iter().cloned().collect();
+        let appended = match self.append_txn(node, src, txn, msgs).await {
+            Ok(appended) => appended,
+            Err(e) => {
+                // Nobody but us can commit the transaction, so we don't need to record that we don't.
+                // If some leaders don't get the marker, they abort it once it times out.
+                let _ = self.end_txn(node, src, txn, keys, Marker::Abort).await;
+                return Err(Error::new(
+                    ErrorCode::Abort,
+                    format!("transaction {txn} aborted: {e}"),
+                ));
+            }
+        };
+        let marker = self.decide(node, txn, Marker::Commit).await?;
+        if let Err(e) = self.end_txn(node, src, txn, keys, marker).await {
+            // The outcome is decided, so the leaders will find out about it once the transaction times out.
+            eprintln!("Failed to end transaction {txn}: {e}");
+        }
+        if marker == Marker::Abort {
+            return Err(Error::new(
+                ErrorCode::Abort,
+                format!("transaction {txn} timed out"),
+            ));
+        }
+        let mut offsets: HashMap<String, VecDeque<u64>> = appended
+            .into_iter()
+            .map(|(key, offsets)| (key, offsets.into()))
+            .collect();
+        Ok(msgs
+            .iter()
+            .map(|(key, _)| {
+                offsets
+                    .get_mut(key)
+                    .and_then(VecDeque::pop_front)
+                    .expect("every message was appended")
+            })
+            .collect())
+    }
+
+    /// A transaction id that no other transaction has, from a counter in lin-kv.
+    async fn next_txn(&self, node: &Node) -> Result<u64, Error> {
+        loop {
+            let next = match self.kv.read(node, TXN_COUNTER).await {
+                Err(Error {
+                    code: ErrorCode::KeyDoesNotExist,
+                    ..
+                }) => 0,
+                result => result?,
+            };
+            match self
+                .kv
+                .cas_or_create(node, TXN_COUNTER, &next, &(next + 1))
+                .await
+            {
+                Ok(()) => return Ok(next),
+                // Another transaction got the id first, or we don't know whether we did.
+                // Either way the next attempt gets an id that nobody else has, at worst skipping one.
+                Err(Error {
+                    code: ErrorCode::PreconditionFailed | ErrorCode::Timeout,
+                    ..
+                }) => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Record in lin-kv that `txn` ended with `marker`, unless it's already decided otherwise,
+    /// and return how it ended.
+    async fn decide(&self, node: &Node, txn: u64, marker: Marker) -> Result<Marker, Error> {
+        match self
+            .kv
+            .cas_or_create(node, decision_key(txn), &marker, &marker)
+            .await
+        {
+            Ok(()) => Ok(marker),
+            Err(Error {
+                code: ErrorCode::PreconditionFailed,
+                ..
+            }) => Ok(opposite(marker)),
+            // We might have recorded it even so.
+            Err(e) => Err(indefinite(e)),
+        }
+    }
+
+    /// Append `msgs` as part of `txn` to the logs of their keys, and return their offsets by key.
+    pub(super) async fn append_txn(
+        self: &Rc<Self>,
+        node: &Rc<Node>,
+        src: &str,
+        txn: u64,
+        msgs: &[(String, u64)],
+    ) -> Result<HashMap<String, Vec<u64>>, Error> {
+        let keys: BTreeSet<&String> = msgs.iter().map(|(key, _)| key).collect();
+        let keys = keys.into_iter().cloned().collect();
+        let request = |keys: Vec<String>| KafkaBody::AppendTxn {
+            txn,
+            msgs: msgs
+                .iter()
+                .filter(|(key, _)| keys.contains(key))
+                .cloned()
+                .collect(),
+        };
+        // Appending the messages twice would duplicate them, and the coordinator aborts anyway.
+        let (local, replies) = self.route(node, src, keys, false, request).await?;
+        let mut offsets = HashMap::new();
+        {
+            let mut partitions = self.partitions.lock().await;
+            for key in &local {
+                let partition = self.partition(&mut partitions, key);
+                if let TxnState::Ended(_) = partition.log.txn_state(txn) {
+                    return Err(Error::new(
+                        ErrorCode::Abort,
+                        format!("transaction {txn} has already ended"),
+                    ));
+                }
+                let epoch = partition.epoch;
+                let appended = msgs
+                    .iter()
+                    .filter(|(k, _)| k == key)
+                    .map(|(_, msg)| partition.log.append_txn(txn, *msg, None, epoch))
+                    .collect::<Result<Vec<u64>, _>>()
+                    .map_err(io_error)?;
+                if self.config.fsync == FsyncPolicy::Always {
+                    partition.log.sync().map_err(io_error)?;
+                }
+                offsets.insert(key.clone(), appended);
+            }
+        }
+        for key in local {
+            self.replicate(node, &key).await.map_err(indefinite)?;
+        }
+        for reply in replies {
+            let KafkaBody::AppendTxnOk { offsets: appended } = reply else {
+                return Err(Error::new(
+                    ErrorCode::MalformedRequest,
+                    format!("unexpected reply to forwarded append: {reply:?}"),
+                ));
+            };
+            offsets.extend(appended);
+        }
+        Ok(offsets)
+    }
+
+    /// Append `marker` to the logs of `keys` in which `txn` is still open.
+    pub(super) async fn end_txn(
+        self: &Rc<Self>,
+        node: &Rc<Node>,
+        src: &str,
+        txn: u64,
+        keys: Vec<String>,
+        marker: Marker,
+    ) -> Result<(), Error> {
+        let request = |keys| KafkaBody::EndTxn { txn, keys, marker };
+        // Ending a transaction again does nothing.
+        let (local, _) = self.route(node, src, keys, true, request).await?;
+        {
+            let mut partitions = self.partitions.lock().await;
+            for key in &local {
+                let partition = self.partition(&mut partitions, key);
+                if partition.log.txn_state(txn) == TxnState::Open {
+                    let epoch = partition.epoch;
+                    partition
+                        .log
+                        .append_txn(txn, 0, Some(marker), epoch)
+                        .map_err(io_error)?;
+                    if self.config.fsync == FsyncPolicy::Always {
+                        partition.log.sync().map_err(io_error)?;
+                    }
+                }
+                partition.open_since.remove(&txn);
+            }
+        }
+        for key in local {
+            self.replicate(node, &key).await.map_err(indefinite)?;
+        }
+        Ok(())
+    }
+
+    /// Periodically end the transactions that have been open in the logs that we lead
+    /// for longer than the transaction timeout, as their coordinator decided or, if it
+    /// hasn't yet, by aborting them.
+    pub(super) async fn resolve_periodically(self: Rc<Self>, node: Rc<Node>) {
+        let mut interval = time::interval_at(Instant::now() + CLEANUP_INTERVAL, CLEANUP_INTERVAL);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            for (key, txn) in self.abandoned_txns(&node).await {
+                let resolved = match self.decide(&node, txn, Marker::Abort).await {
+                    Ok(marker) => {
+                        self.end_txn(&node, node.id(), txn, vec![key.clone()], marker)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = resolved {
+                    eprintln!("Failed to resolve transaction {txn} of {key}: {e}");
+                }
+            }
+        }
+    }
+
+    /// The transactions that have been open for too long in the logs that we lead, with their keys.
+    async fn abandoned_txns(&self, node: &Node) -> Vec<(String, u64)> {
+        let now = Instant::now();
+        let mut abandoned = Vec::new();
+        let mut partitions = self.partitions.lock().await;
+        for (key, partition) in partitions.iter_mut() {
+            let leading = partition
+                .leadership
+                .as_ref()
+                .is_some_and(|leadership| leadership.leader == node.id());
+            let open: Vec<u64> = if leading {
+                partition.log.open_txns().collect()
+            } else {
+                Vec::new()
+            };
+            partition.open_since.retain(|txn, _| open.contains(txn));
+            for txn in open {
+                let since = *partition.open_since.entry(txn).or_insert(now);
+                if now.duration_since(since) >= self.config.txn_timeout {
+                    abandoned.push((key.clone(), txn));
+                }
+            }
+        }
+        abandoned.sort();
+        abandoned
+    }
+}
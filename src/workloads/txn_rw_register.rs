@@ -0,0 +1,453 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+use tokio::task;
+use tokio::time::Duration;
+
+use crate::mvcc::Mvcc;
+use crate::{Error, ErrorCode, Handler, KvClient, KvService, Message, Node, RetryPolicy};
+
+/// The lin-kv key of the root of the database in serializable mode.
+const ROOT: &str = "root";
+
+/// How long we wait for lin-kv to store a snapshot or to swap the root over to it.
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// 6. Totally-Available Transactions challenge
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum TxnBody {
+    Txn {
+        txn: Vec<MicroOp>,
+    },
+    TxnOk {
+        txn: Vec<MicroOp>,
+    },
+    // 6b. Custom messages to copy the writes of a transaction to the other nodes.
+    Replicate {
+        ts: Timestamp,
+        /// `[key, value]` of the last write to every key.
+        writes: Vec<(u64, u64)>,
+    },
+    ReplicateOk,
+}
+
+/// A single read or write of a transaction, as `["r", key, null]` or `["w", key, value]`.
+/// Replies fill in the values of the reads, which stay `null` for keys that were never written.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MicroOp(pub OpKind, pub u64, pub Option<u64>);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpKind {
+    #[serde(rename = "r")]
+    Read,
+    #[serde(rename = "w")]
+    Write,
+}
+
+/// When a transaction wrote, as a counter of the node that ran it, with the node's ID
+/// to break ties. Every transaction that writes has a timestamp of its own.
+///
+/// With [`Consistency::ReadCommitted`], the counters are Lamport clocks: nodes move theirs past
+/// the timestamps of the writes they receive, so a transaction is always later than the ones
+/// whose writes it could have read.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp {
+    pub clock: u64,
+    pub node: String,
+}
+
+/// Which anomalies the transactions of a run are protected against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Consistency {
+    /// No dirty writes (G0).
+    ReadUncommitted,
+    /// No dirty writes, and no aborted reads, intermediate reads or circular information flow (G1).
+    ReadCommitted,
+    /// Transactions take effect one at a time, in an order that respects real time.
+    /// Needs lin-kv, so it's not available while lin-kv can't be reached.
+    Serializable,
+}
+
+impl FromStr for Consistency {
+    type Err = anyhow::Error;
+
+    /// The names of Maelstrom's consistency models, e.g. `read-committed`.
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "read-uncommitted" => Consistency::ReadUncommitted,
+            "read-committed" => Consistency::ReadCommitted,
+            "serializable" | "strict-serializable" => Consistency::Serializable,
+            other => bail!("unknown consistency model {other}"),
+        })
+    }
+}
+
+/// How the transactions of a run behave.
+#[derive(Debug, Clone)]
+pub struct TxnConfig {
+    pub consistency: Consistency,
+}
+
+impl Default for TxnConfig {
+    fn default() -> Self {
+        Self {
+            consistency: Consistency::ReadCommitted,
+        }
+    }
+}
+
+impl TxnConfig {
+    /// The default configuration, changed by the `TXN_CONSISTENCY` environment variable if it is set.
+    pub fn from_env() -> Result<Self> {
+        let mut config = Self::default();
+        if let Ok(consistency) = env::var("TXN_CONSISTENCY") {
+            config.consistency = consistency.parse().context("invalid TXN_CONSISTENCY")?;
+        }
+        Ok(config)
+    }
+}
+
+/// Which snapshot of the database is the current one in serializable mode, as stored in lin-kv.
+///
+/// Snapshots are stored in lin-kv as well, but never change once they are written,
+/// so nodes can keep the latest one that they've seen around.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+struct Root {
+    /// The number of transactions that have committed writes.
+    version: u64,
+    /// The lin-kv key of the snapshot, or `None` while the database is empty.
+    snapshot: Option<String>,
+}
+
+/// Our copy of the database in serializable mode.
+#[derive(Debug, Default)]
+struct Db {
+    /// The latest root that we've seen.
+    root: Root,
+    /// The values of all keys, versioned by the roots that changed them.
+    versions: Mvcc<u64, u64, u64>,
+}
+
+impl Db {
+    /// Move on to `root`, unless we've seen a later one already. `values` has to include
+    /// all keys that changed since our current root, but can be the whole snapshot.
+    fn advance(&mut self, root: Root, values: impl IntoIterator<Item = (u64, u64)>) {
+        if root.version <= self.root.version {
+            return;
+        }
+        for (key, value) in values {
+            if self.versions.latest(&key).map(|(_, &latest)| latest) != Some(value) {
+                self.versions.write(key, root.version, value);
+            }
+        }
+        // Transactions only read the latest root, and check later versions for conflicts.
+        self.versions.gc(&root.version);
+        self.root = root;
+    }
+
+    /// The snapshot of our latest root with `writes` on top, in key order.
+    fn entries(&self, writes: &BTreeMap<u64, u64>) -> Vec<(u64, u64)> {
+        let mut entries: BTreeMap<u64, u64> = self
+            .versions
+            .snapshot(&self.root.version)
+            .map(|(&key, &value)| (key, value))
+            .collect();
+        entries.extend(writes);
+        entries.into_iter().collect()
+    }
+}
+
+/// A key-value store of which every node has a full copy (challenges 6a to 6c),
+/// or which lives in lin-kv with [`Consistency::Serializable`].
+///
+/// Transactions run on the node that receives them, with the lock on its whole store held,
+/// so nobody sees the effects of a transaction halfway through. Their writes are then copied
+/// to all other nodes in the background, retrying until they arrive, which keeps the nodes
+/// available even if they can't reach each other.
+///
+/// Every key keeps the write with the latest timestamp, whatever order the writes arrive in,
+/// so all nodes end up with the same values. All writes of a transaction have the same timestamp,
+/// so the writes to every key are ordered the same way, and transactions can't overwrite
+/// each other in a cycle (G0).
+///
+/// No transaction is ever aborted, and only the last write of a transaction to each key
+/// leaves the node that ran it, so nobody reads aborted or intermediate values (G1a and G1b).
+/// Whether reads and writes can depend on each other in a cycle (G1c) depends on the clocks,
+/// see [`Timestamp`].
+///
+/// Serializable transactions run against the latest snapshot of the database in lin-kv instead,
+/// like in Datomic. If they write, they store a new snapshot and swap the root over to it
+/// with a CAS, which fails if another transaction got there first. Every node keeps the
+/// latest snapshot it has seen in an [`Mvcc`] store, versioned by root, so it can tell whether
+/// those transactions changed any of the keys that this one read or wrote. If they didn't,
+/// the transaction would have had the same effect after them, so it's tried again on top
+/// of them. Otherwise it fails, and the client can try again with a fresh snapshot.
+pub struct TxnRwRegister {
+    config: TxnConfig,
+    store: Mutex<Store>,
+    kv: KvClient,
+    /// Sends the writes of commits only once: if the first CAS of the root went through
+    /// but its reply got lost, a second one would fail and look like a conflict.
+    commit_kv: KvClient,
+    /// The latest snapshot of the database that we've seen, in serializable mode.
+    db: Mutex<Db>,
+}
+
+impl Default for TxnRwRegister {
+    fn default() -> Self {
+        Self::new(TxnConfig::default())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Store {
+    /// Counts the transactions of this node that wrote something, see [`Timestamp`].
+    clock: u64,
+    registers: HashMap<u64, Register>,
+}
+
+#[derive(Debug)]
+struct Register {
+    value: u64,
+    written: Timestamp,
+}
+
+impl Store {
+    /// Run `txn`, and return it with the values of the reads filled in, along with its
+    /// timestamp and the last value it wrote to every key, if it wrote anything.
+    fn execute(
+        &mut self,
+        node: &Node,
+        txn: &[MicroOp],
+    ) -> (Vec<MicroOp>, Option<Timestamp>, Vec<(u64, u64)>) {
+        // Our own writes are in the store unless a later one from another node has won.
+        let (txn, writes) = execute(txn, |key| self.registers.get(&key).map(|r| r.value));
+        let writes: Vec<(u64, u64)> = writes.into_iter().collect();
+        if writes.is_empty() {
+            return (txn, None, writes);
+        }
+        self.clock += 1;
+        let ts = Timestamp {
+            clock: self.clock,
+            node: node.id().to_owned(),
+        };
+        self.apply(&ts, &writes);
+        (txn, Some(ts), writes)
+    }
+
+    /// Move our clock past `ts`, so that our next transactions are later than it.
+    fn observe(&mut self, ts: &Timestamp) {
+        self.clock = self.clock.max(ts.clock);
+    }
+
+    /// Apply the writes of a transaction, wherever they are later than what we have.
+    fn apply(&mut self, ts: &Timestamp, writes: &[(u64, u64)]) {
+        for &(key, value) in writes {
+            let register = Register {
+                value,
+                written: ts.clone(),
+            };
+            match self.registers.get_mut(&key) {
+                Some(known) if known.written >= *ts => {}
+                Some(known) => *known = register,
+                None => {
+                    self.registers.insert(key, register);
+                }
+            }
+        }
+    }
+}
+
+/// Run `txn` on top of the values that `read` returns, and return it with the values of the reads
+/// filled in, along with the last value it wrote to every key.
+fn execute(
+    txn: &[MicroOp],
+    read: impl Fn(u64) -> Option<u64>,
+) -> (Vec<MicroOp>, BTreeMap<u64, u64>) {
+    let mut writes = BTreeMap::new();
+    let txn = txn
+        .iter()
+        .map(|&MicroOp(kind, key, value)| match kind {
+            OpKind::Read => MicroOp(kind, key, writes.get(&key).copied().or_else(|| read(key))),
+            OpKind::Write => {
+                if let Some(value) = value {
+                    writes.insert(key, value);
+                }
+                MicroOp(kind, key, value)
+            }
+        })
+        .collect();
+    (txn, writes)
+}
+
+impl TxnRwRegister {
+    pub fn new(config: TxnConfig) -> Self {
+        let commit_policy = RetryPolicy {
+            initial_backoff: COMMIT_TIMEOUT,
+            max_attempts: Some(1),
+            ..RetryPolicy::default()
+        };
+        Self {
+            config,
+            store: Mutex::default(),
+            kv: KvClient::new(KvService::Lin),
+            commit_kv: KvClient::new(KvService::Lin).with_retry_policy(commit_policy),
+            db: Mutex::default(),
+        }
+    }
+
+    /// Run `txn` against the current snapshot of the database, and commit its writes, if any.
+    /// Fails with a txn-conflict if another transaction changed the same keys in the meantime,
+    /// and with a timeout if we can't tell whether it committed.
+    async fn run_serializable(&self, node: &Node, txn: &[MicroOp]) -> Result<Vec<MicroOp>, Error> {
+        self.catch_up(node).await?;
+        let mut db = self.db.lock().await;
+        let mut start = db.root.clone();
+        let (txn, writes) = execute(txn, |key| db.versions.read(&key, &start.version).copied());
+        // Read-only transactions are done: the root was current at some point since they started.
+        if writes.is_empty() {
+            return Ok(txn);
+        }
+        let keys: BTreeSet<u64> = txn.iter().map(|&MicroOp(_, key, _)| key).collect();
+        let mut entries = db.entries(&writes);
+        drop(db);
+        // Every failed CAS means that another transaction committed, so this doesn't go on forever.
+        loop {
+            if self.commit(node, &start, entries, &writes).await? {
+                return Ok(txn);
+            }
+            self.catch_up(node).await?;
+            db = self.db.lock().await;
+            if let Some(key) = db.versions.conflict(&keys, &start.version) {
+                return Err(Error::new(
+                    ErrorCode::TxnConflict,
+                    format!("another transaction changed key {key}"),
+                ));
+            }
+            start = db.root.clone();
+            entries = db.entries(&writes);
+            drop(db);
+        }
+    }
+
+    /// Store `entries` as the snapshot after `start`, and swap the root over to it.
+    /// Returns whether that worked, or `false` if another transaction got there first.
+    async fn commit(
+        &self,
+        node: &Node,
+        start: &Root,
+        entries: Vec<(u64, u64)>,
+        writes: &BTreeMap<u64, u64>,
+    ) -> Result<bool, Error> {
+        let snapshot = format!("snapshot/{}-{}", node.id(), node.next_msg_id());
+        if let Err(e) = self
+            .commit_kv
+            .write(node, snapshot.as_str(), &entries)
+            .await
+        {
+            // Nothing refers to the snapshot yet, so the transaction definitely didn't commit.
+            return Err(Error::new(
+                ErrorCode::Abort,
+                format!("failed to store snapshot: {e}"),
+            ));
+        }
+        let next = Root {
+            version: start.version + 1,
+            snapshot: Some(snapshot),
+        };
+        match self.commit_kv.cas_or_create(node, ROOT, start, &next).await {
+            Ok(()) => {
+                let writes = writes.iter().map(|(&key, &value)| (key, value));
+                self.db.lock().await.advance(next, writes);
+                Ok(true)
+            }
+            // The snapshot that we wrote is never used.
+            Err(Error {
+                code: ErrorCode::PreconditionFailed,
+                ..
+            }) => Ok(false),
+            // Timeouts are indefinite: the CAS might have gone through.
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Bring our copy of the database up to the current root in lin-kv.
+    async fn catch_up(&self, node: &Node) -> Result<(), Error> {
+        let root: Root = match self.kv.read(node, ROOT).await {
+            Err(Error {
+                code: ErrorCode::KeyDoesNotExist,
+                ..
+            }) => Root::default(),
+            result => result?,
+        };
+        let Some(snapshot) = &root.snapshot else {
+            return Ok(());
+        };
+        if root.version <= self.db.lock().await.root.version {
+            return Ok(());
+        }
+        let entries: Vec<(u64, u64)> = self.kv.read(node, snapshot.as_str()).await?;
+        self.db.lock().await.advance(root, entries);
+        Ok(())
+    }
+
+    /// Send the writes of a transaction to all other nodes, retrying until they arrive.
+    fn replicate(&self, node: &Rc<Node>, ts: Timestamp, writes: Vec<(u64, u64)>) {
+        for peer in node.node_ids().iter().filter(|&id| id != node.id()) {
+            let node = node.clone();
+            let peer = peer.clone();
+            let replicate = TxnBody::Replicate {
+                ts: ts.clone(),
+                writes: writes.clone(),
+            };
+            task::spawn_local(async move { node.rpc::<_, TxnBody>(peer, replicate).await });
+        }
+    }
+}
+
+impl Handler for TxnRwRegister {
+    type Body = TxnBody;
+
+    async fn handle(self: Rc<Self>, node: Rc<Node>, msg: Message<TxnBody>) -> Result<()> {
+        match &msg.body.inner {
+            TxnBody::Txn { txn } => {
+                if txn.iter().any(|op| op.0 == OpKind::Write && op.2.is_none()) {
+                    bail!(Error::new(
+                        ErrorCode::MalformedRequest,
+                        "writes need a value"
+                    ));
+                }
+                let txn = if self.config.consistency == Consistency::Serializable {
+                    self.run_serializable(&node, txn).await?
+                } else {
+                    let (txn, ts, writes) = self.store.lock().await.execute(&node, txn);
+                    if let Some(ts) = ts {
+                        self.replicate(&node, ts, writes);
+                    }
+                    txn
+                };
+                let reply = node.reply(&msg, TxnBody::TxnOk { txn });
+                node.send(&reply).await?;
+            }
+            TxnBody::Replicate { ts, writes } => {
+                let mut store = self.store.lock().await;
+                if self.config.consistency >= Consistency::ReadCommitted {
+                    store.observe(ts);
+                }
+                store.apply(ts, writes);
+                drop(store);
+                node.send(&node.reply(&msg, TxnBody::ReplicateOk)).await?;
+            }
+            _ => bail!(Error::from(ErrorCode::NotSupported)),
+        }
+
+        Ok(())
+    }
+}
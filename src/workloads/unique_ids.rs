@@ -0,0 +1,50 @@
+use std::rc::Rc;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{Error, ErrorCode, Handler, Message, Node};
+
+/// 2. Unique ID generation challenge
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum UniqueIdsBody {
+    Generate,
+    GenerateOk { id: String },
+}
+
+/// Generates globally unique IDs without coordinating with the other nodes.
+pub struct Guid;
+
+impl Handler for Guid {
+    type Body = UniqueIdsBody;
+
+    async fn handle(self: Rc<Self>, node: Rc<Node>, msg: Message<UniqueIdsBody>) -> Result<()> {
+        match &msg.body.inner {
+            UniqueIdsBody::Generate => {
+                let msg_id = node.next_msg_id();
+                let reply = msg.reply(
+                    msg_id,
+                    UniqueIdsBody::GenerateOk {
+                        // Since both our node ID and our current message ID are unique,
+                        // we can combine them to create a globally unique ID.
+                        // Note that this assumption breaks in conditions where node IDs can be
+                        // reused. For example, in a scenario where nodes can crash/stop and new nodes
+                        // can later join the cluster and receive a previously used ID.
+                        // This scheme also has other properties that may be undesireable for certain
+                        // applications: the IDs are sequential (per node), and the IDs allow identifying the
+                        // origin node that generated it.
+                        // Depending on the requirements, it may be better to use a scheme such as the ones
+                        // described in RFC9562 (see: https://datatracker.ietf.org/doc/html/rfc9562).
+                        id: format!("{}-{}", node.id(), msg_id),
+                    },
+                );
+                node.send(&reply).await?;
+            }
+            _ => bail!(Error::from(ErrorCode::NotSupported)),
+        }
+
+        Ok(())
+    }
+}
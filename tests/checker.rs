@@ -0,0 +1,184 @@
+use dist_sys_challenge::checker::{self, History, OpType};
+
+#[test]
+fn parses_maelstrom_histories() {
+    let txt = History::parse(
+        "0\t:invoke\t:broadcast\t0\n\
+         :nemesis\t:info\t:start-partition\t:majority\n\
+         0\t:ok\t:broadcast\t0\n\
+         1\t:invoke\t:read\tnil\n\
+         1\t:ok\t:read\t[0]\n",
+    )
+    .unwrap();
+    let edn = History::parse(
+        "{:type :invoke, :f :broadcast, :value 0, :process 0, :time 1, :index 0}\n\
+         {:type :info, :f :start-partition, :value :majority, :process :nemesis, :index 1}\n\
+         {:type :ok, :f :broadcast, :value 0, :process 0, :time 2, :index 2}\n\
+         {:type :invoke, :f :read, :value nil, :process 1, :time 3, :index 3}\n\
+         {:type :ok, :f :read, :value [0], :process 1, :time 4, :index 4}\n",
+    )
+    .unwrap();
+    assert_eq!(txt.ops(), edn.ops());
+    assert_eq!(txt.ops().len(), 4);
+    assert_eq!(txt.ops()[3].kind, OpType::Ok);
+    assert!(checker::broadcast::check(&txt, 0).is_empty());
+}
+
+#[test]
+fn broadcast_finds_missing_and_unexpected_values() {
+    let history = History::parse(
+        "0\t:invoke\t:broadcast\t1\n\
+         0\t:ok\t:broadcast\t1\n\
+         0\t:invoke\t:broadcast\t2\n\
+         0\t:ok\t:broadcast\t2\n\
+         1\t:invoke\t:read\tnil\n\
+         1\t:ok\t:read\t[1 3]\n",
+    )
+    .unwrap();
+    let anomalies = checker::broadcast::check(&history, 0);
+    assert_eq!(anomalies.len(), 2, "{anomalies:?}");
+}
+
+#[test]
+fn broadcast_only_requires_final_reads_to_be_complete() {
+    let history = History::parse(
+        "0\t:invoke\t:broadcast\t1\n\
+         0\t:ok\t:broadcast\t1\n\
+         1\t:invoke\t:read\tnil\n\
+         1\t:ok\t:read\t[]\n\
+         1\t:invoke\t:read\tnil\n\
+         1\t:ok\t:read\t[1]\n",
+    )
+    .unwrap();
+    let anomalies = checker::broadcast::check(&history, 4);
+    assert!(anomalies.is_empty(), "{anomalies:?}");
+    let anomalies = checker::broadcast::check(&history, 0);
+    assert_eq!(anomalies.len(), 1, "{anomalies:?}");
+    let anomalies = checker::broadcast::check(&history, 6);
+    assert_eq!(anomalies.len(), 1, "{anomalies:?}");
+}
+
+#[test]
+fn g_counter_finds_reads_out_of_bounds() {
+    let history = History::parse(
+        "0\t:invoke\t:add\t2\n\
+         0\t:ok\t:add\t2\n\
+         1\t:invoke\t:add\t3\n\
+         2\t:invoke\t:read\tnil\n\
+         2\t:ok\t:read\t5\n\
+         2\t:invoke\t:read\tnil\n\
+         2\t:ok\t:read\t1\n\
+         1\t:info\t:add\t3\n\
+         2\t:invoke\t:read\tnil\n\
+         2\t:ok\t:read\t6\n",
+    )
+    .unwrap();
+    let anomalies = checker::g_counter::check(&history);
+    let indices: Vec<_> = anomalies.iter().map(|a| a.index).collect();
+    assert_eq!(indices, [Some(5), Some(8)], "{anomalies:?}");
+}
+
+#[test]
+fn kafka_finds_lost_writes_and_reordered_offsets() {
+    let history = History::parse(
+        "0\t:invoke\t:send\t[[:send 1 10]]\n\
+         0\t:ok\t:send\t[[:send 1 [0 10]]]\n\
+         0\t:invoke\t:send\t[[:send 1 11]]\n\
+         0\t:ok\t:send\t[[:send 1 [1 11]]]\n\
+         0\t:invoke\t:send\t[[:send 1 12]]\n\
+         0\t:ok\t:send\t[[:send 1 [2 12]]]\n\
+         1\t:invoke\t:poll\t[[:poll]]\n\
+         1\t:ok\t:poll\t[[:poll {1 [[0 10] [2 12]]}]]\n",
+    )
+    .unwrap();
+    let anomalies = checker::kafka::check(&history);
+    assert_eq!(anomalies.len(), 1, "{anomalies:?}");
+    assert!(anomalies[0].description.contains("losing 11"));
+
+    let history = History::parse(
+        "0\t:invoke\t:send\t[[:send 1 10]]\n\
+         0\t:ok\t:send\t[[:send 1 [5 10]]]\n\
+         1\t:invoke\t:send\t[[:send 1 11]]\n\
+         1\t:ok\t:send\t[[:send 1 [3 11]]]\n\
+         2\t:invoke\t:poll\t[[:poll]]\n\
+         2\t:ok\t:poll\t[[:poll {1 [[3 12]]}]]\n",
+    )
+    .unwrap();
+    let anomalies = checker::kafka::check(&history);
+    assert_eq!(anomalies.len(), 2, "{anomalies:?}");
+}
+
+#[test]
+fn kafka_allows_gaps_but_not_duplicates() {
+    // Compaction removed 10, which has a later message with the same message key.
+    let history = History::parse(
+        "0\t:invoke\t:send\t[[:send 1 10 7]]\n\
+         0\t:ok\t:send\t[[:send 1 [0 10] 7]]\n\
+         0\t:invoke\t:send\t[[:send 1 11]]\n\
+         0\t:ok\t:send\t[[:send 1 [2 11]]]\n\
+         0\t:invoke\t:send\t[[:send 1 12 7]]\n\
+         0\t:ok\t:send\t[[:send 1 [3 12] 7]]\n\
+         1\t:invoke\t:poll\t[[:poll]]\n\
+         1\t:ok\t:poll\t[[:poll {1 [[2 11] [3 12]]}]]\n",
+    )
+    .unwrap();
+    let anomalies = checker::kafka::check(&history);
+    assert!(anomalies.is_empty(), "{anomalies:?}");
+
+    let history = History::parse(
+        "0\t:invoke\t:send\t[[:send 1 10]]\n\
+         0\t:ok\t:send\t[[:send 1 [0 10]]]\n\
+         1\t:invoke\t:poll\t[[:poll]]\n\
+         1\t:ok\t:poll\t[[:poll {1 [[0 10] [1 10]]}]]\n",
+    )
+    .unwrap();
+    let anomalies = checker::kafka::check(&history);
+    assert_eq!(anomalies.len(), 1, "{anomalies:?}");
+    assert!(anomalies[0].description.contains("at both offset 0 and 1"));
+}
+
+#[test]
+fn kafka_reports_empty_micro_ops() {
+    let history = History::parse(
+        "0\t:invoke\t:send\t[[]]\n\
+         0\t:ok\t:send\t[[]]\n",
+    )
+    .unwrap();
+    let anomalies = checker::kafka::check(&history);
+    assert_eq!(anomalies.len(), 1, "{anomalies:?}");
+    assert!(anomalies[0].description.contains("malformed"));
+}
+
+#[test]
+fn register_checks_linearizability() {
+    // The write of 2 is concurrent with both reads, so it can be ordered between them.
+    let history = History::parse(
+        "0\t:invoke\t:write\t[0 1]\n\
+         0\t:ok\t:write\t[0 1]\n\
+         1\t:invoke\t:write\t[0 2]\n\
+         2\t:invoke\t:read\t[0 nil]\n\
+         2\t:ok\t:read\t[0 1]\n\
+         2\t:invoke\t:read\t[0 nil]\n\
+         2\t:ok\t:read\t[0 2]\n\
+         1\t:info\t:write\t[0 2]\n\
+         3\t:invoke\t:cas\t[0 [2 3]]\n\
+         3\t:ok\t:cas\t[0 [2 3]]\n",
+    )
+    .unwrap();
+    assert!(checker::register::check(&history).is_empty());
+
+    // Reading 1 after the write of 2 completed is a stale read.
+    let history = History::parse(
+        "0\t:invoke\t:write\t[0 1]\n\
+         0\t:ok\t:write\t[0 1]\n\
+         0\t:invoke\t:write\t[0 2]\n\
+         0\t:ok\t:write\t[0 2]\n\
+         1\t:invoke\t:read\t[0 nil]\n\
+         1\t:ok\t:read\t[0 1]\n\
+         2\t:invoke\t:read\t[1 nil]\n\
+         2\t:ok\t:read\t[1 nil]\n",
+    )
+    .unwrap();
+    let anomalies = checker::register::check(&history);
+    assert_eq!(anomalies.len(), 1, "{anomalies:?}");
+}
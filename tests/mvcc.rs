@@ -0,0 +1,63 @@
+use dist_sys_challenge::mvcc::Mvcc;
+
+#[test]
+fn snapshot_reads_see_the_versions_before_them() {
+    let mut store = Mvcc::new();
+    store.write("x", 10, 1);
+    store.write("x", 30, 3);
+    // Versions can arrive out of order, and again.
+    store.write("x", 20, 2);
+    store.write("x", 20, 2);
+    store.write("y", 20, 5);
+
+    assert_eq!(store.read(&"x", &5), None);
+    assert_eq!(store.read(&"x", &10), Some(&1));
+    assert_eq!(store.read(&"x", &25), Some(&2));
+    assert_eq!(store.read(&"x", &100), Some(&3));
+    assert_eq!(store.read(&"y", &25), Some(&5));
+    assert_eq!(store.read(&"z", &25), None);
+    assert_eq!(store.latest(&"x"), Some((&30, &3)));
+    assert_eq!(store.versions(), 4);
+
+    let mut snapshot: Vec<_> = store.snapshot(&25).collect();
+    snapshot.sort();
+    assert_eq!(snapshot, [(&"x", &2), (&"y", &5)]);
+    assert_eq!(store.snapshot(&15).collect::<Vec<_>>(), [(&"x", &1)]);
+}
+
+#[test]
+fn the_first_committer_wins() {
+    let mut store = Mvcc::new();
+    store.write("x", 0, 0);
+    store.write("y", 0, 0);
+
+    // Two transactions start from the snapshot at 0, and both write x.
+    assert_eq!(store.commit(&0, 1, [("x", 1), ("y", 1)]), Ok(()));
+    assert_eq!(store.commit(&0, 2, [("z", 2), ("x", 2)]), Err("x"));
+    // The loser wrote nothing, and a transaction that started after the winner can go ahead.
+    assert_eq!(store.latest(&"z"), None);
+    assert_eq!(store.conflict(&["x", "y"], &1), None);
+    assert_eq!(store.commit(&1, 3, [("x", 3)]), Ok(()));
+    assert_eq!(store.read(&"x", &2), Some(&1));
+}
+
+#[test]
+fn garbage_collection_keeps_what_snapshots_can_read() {
+    let mut store = Mvcc::new();
+    for ts in 1..=5 {
+        store.write("x", ts, ts * 10);
+    }
+    store.write("y", 1, 100);
+
+    store.gc(&3);
+    assert_eq!(store.versions(), 4);
+    assert_eq!(store.read(&"x", &3), Some(&30));
+    assert_eq!(store.read(&"x", &4), Some(&40));
+    assert_eq!(store.read(&"y", &3), Some(&100));
+    // Snapshots from before the horizon can't read what they used to anymore.
+    assert_eq!(store.read(&"x", &2), None);
+
+    store.gc(&10);
+    assert_eq!(store.versions(), 2);
+    assert_eq!(store.latest(&"x"), Some((&5, &50)));
+}
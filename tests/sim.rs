@@ -0,0 +1,1306 @@
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::io::Write;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs, process};
+
+use serde_json::json;
+use tokio::time::{self, Instant};
+
+use dist_sys_challenge::checker::{self, History, OpType};
+use dist_sys_challenge::sim::{self, Client, Cluster, Config, Event, EventKind};
+use dist_sys_challenge::workloads::broadcast::{BatchedBroadcast, Broadcast, BroadcastBody};
+use dist_sys_challenge::workloads::g_counter::{GCounter, GCounterBody};
+use dist_sys_challenge::workloads::kafka::{
+    Kafka, KafkaBody, KafkaConfig, Marker, PollLimits, Retention,
+};
+use dist_sys_challenge::workloads::txn_rw_register::{
+    Consistency, MicroOp, OpKind, TxnBody, TxnConfig, TxnRwRegister,
+};
+use dist_sys_challenge::{Error, ErrorCode, KvBody};
+
+/// Send the topology to every node.
+async fn send_topology(cluster: &Cluster, client: &Client) {
+    for id in cluster.node_ids() {
+        let topology = BroadcastBody::Topology {
+            topology: HashMap::new(),
+        };
+        let reply: BroadcastBody = client.rpc(id, topology).await.unwrap();
+        assert!(matches!(reply, BroadcastBody::TopologyOk));
+    }
+}
+
+/// Broadcast `values` round-robin over the nodes, and return the values that each node reads after `settle`.
+async fn broadcast(cluster: &Cluster, values: &[u64], settle: Duration) -> Vec<BTreeSet<u64>> {
+    let client = cluster.client();
+    send_topology(cluster, &client).await;
+    for (&message, id) in values.iter().zip(cluster.node_ids().iter().cycle()) {
+        let reply: BroadcastBody = client
+            .rpc(id, BroadcastBody::Broadcast { message })
+            .await
+            .unwrap();
+        assert!(matches!(reply, BroadcastBody::BroadcastOk));
+    }
+    time::sleep(settle).await;
+
+    let mut reads = Vec::new();
+    for id in cluster.node_ids() {
+        match client.rpc(id, BroadcastBody::Read).await.unwrap() {
+            BroadcastBody::ReadOk { messages } => reads.push(messages.into_iter().collect()),
+            other => panic!("unexpected reply {other:?}"),
+        }
+    }
+    reads
+}
+
+#[test]
+fn broadcast_reaches_all_nodes() {
+    sim::run(async {
+        let config = Config {
+            node_count: 10,
+            ..Config::default()
+        };
+        let cluster = Cluster::start(config, Broadcast::default).await.unwrap();
+        let values: Vec<u64> = (0..50).collect();
+        let reads = broadcast(&cluster, &values, Duration::from_millis(200)).await;
+        for read in reads {
+            assert_eq!(read, values.iter().copied().collect());
+        }
+    });
+}
+
+/// Broadcast while the network is partitioned and drops messages,
+/// check that all acknowledged values arrive everywhere once it heals, and return the trace.
+fn lossy_broadcast(config: Config) -> Vec<Event> {
+    sim::run(async {
+        let cluster = Cluster::start(config, Broadcast::default).await.unwrap();
+        let client = cluster.client();
+        send_topology(&cluster, &client).await;
+        cluster.partition(&[&["n0", "n1"], &["n2", "n3", "n4"]]);
+        cluster.set_drop_rate(0.2);
+        let heal = async {
+            time::sleep(Duration::from_millis(300)).await;
+            cluster.heal();
+        };
+        // Drops also affect the clients, so only count the broadcasts that were acknowledged.
+        let mut acked = BTreeSet::new();
+        let broadcasts = async {
+            for message in 0..20 {
+                let id = &cluster.node_ids()[message as usize % 5];
+                if client
+                    .rpc::<_, BroadcastBody>(id, BroadcastBody::Broadcast { message })
+                    .await
+                    .is_ok()
+                {
+                    acked.insert(message);
+                }
+            }
+        };
+        tokio::join!(heal, broadcasts);
+        cluster.set_drop_rate(0.0);
+        time::sleep(Duration::from_secs(3)).await;
+        let settled = History::from_trace(&cluster.trace()).ops().len();
+
+        for id in cluster.node_ids() {
+            match client.rpc(id, BroadcastBody::Read).await.unwrap() {
+                BroadcastBody::ReadOk { messages } => {
+                    let read: BTreeSet<u64> = messages.into_iter().collect();
+                    assert!(read.is_superset(&acked), "{id} is missing values");
+                }
+                other => panic!("unexpected reply {other:?}"),
+            }
+        }
+        let trace = cluster.trace();
+        let anomalies = checker::broadcast::check(&History::from_trace(&trace), settled);
+        assert!(anomalies.is_empty(), "{anomalies:?}");
+        trace
+    })
+}
+
+#[test]
+fn broadcast_survives_partitions_and_drops() {
+    lossy_broadcast(Config {
+        node_count: 5,
+        ..Config::default()
+    });
+}
+
+#[test]
+fn simulation_replays_from_seed() {
+    let config = Config {
+        node_count: 5,
+        seed: 42,
+        ..Config::default()
+    };
+    let trace = lossy_broadcast(config.clone());
+    assert!(trace.iter().any(|event| event.kind == EventKind::Dropped));
+    assert_eq!(trace, lossy_broadcast(config.clone()));
+    let other_seed = Config { seed: 43, ..config };
+    assert_ne!(trace, lossy_broadcast(other_seed));
+}
+
+#[test]
+fn batched_broadcast_reaches_all_nodes() {
+    sim::run(async {
+        let config = Config {
+            node_count: 25,
+            ..Config::default()
+        };
+        let cluster = Cluster::start(config, BatchedBroadcast::default)
+            .await
+            .unwrap();
+        let values: Vec<u64> = (0..100).collect();
+        let reads = broadcast(&cluster, &values, Duration::from_millis(1000)).await;
+        for read in reads {
+            assert_eq!(read, values.iter().copied().collect());
+        }
+    });
+}
+
+#[test]
+fn g_counter_adds_up() {
+    sim::run(async {
+        let config = Config {
+            node_count: 3,
+            ..Config::default()
+        };
+        let cluster = Cluster::start(config, GCounter::default).await.unwrap();
+        let clients: Vec<_> = (0..3).map(|_| cluster.client()).collect();
+        // Add concurrently from all clients, so that the CAS operations conflict.
+        let adds = clients.iter().enumerate().map(|(i, client)| async move {
+            for delta in 1..=10 {
+                let id = format!("n{}", (i + delta as usize) % 3);
+                let reply: GCounterBody =
+                    client.rpc(id, GCounterBody::Add { delta }).await.unwrap();
+                assert!(matches!(reply, GCounterBody::AddOk));
+            }
+        });
+        futures::future::join_all(adds).await;
+
+        for id in cluster.node_ids() {
+            match clients[0].rpc(id, GCounterBody::Read).await.unwrap() {
+                GCounterBody::ReadOk { value } => assert_eq!(value, 3 * 55),
+                other => panic!("unexpected reply {other:?}"),
+            }
+        }
+        let anomalies = checker::g_counter::check(&History::from_trace(&cluster.trace()));
+        assert!(anomalies.is_empty(), "{anomalies:?}");
+    });
+}
+
+/// Start a cluster of kafka nodes with the configuration `kafka`.
+async fn kafka_cluster(config: Config, kafka: KafkaConfig) -> Cluster {
+    Cluster::start(config, || Kafka::new(kafka.clone()))
+        .await
+        .unwrap()
+}
+
+/// A send of `msg` to `key`, without a message key or a producer.
+fn send(key: &str, msg: u64) -> KafkaBody {
+    KafkaBody::Send {
+        key: key.into(),
+        msg,
+        msg_key: None,
+        producer_id: None,
+        seq: None,
+    }
+}
+
+/// A poll of the keys from their offsets, within the node's limits.
+fn poll(offsets: &[(&str, u64)]) -> KafkaBody {
+    KafkaBody::Poll {
+        offsets: offsets
+            .iter()
+            .map(|&(key, offset)| (key.into(), offset))
+            .collect(),
+        max_messages: None,
+        max_bytes: None,
+        max_wait_ms: None,
+    }
+}
+
+#[test]
+fn kafka_log() {
+    sim::run(async {
+        let cluster = kafka_cluster(Config::default(), KafkaConfig::default()).await;
+        let client = cluster.client();
+        for (i, key) in ["a", "b", "a", "a"].into_iter().enumerate() {
+            let send = send(key, 100 + i as u64);
+            client.rpc::<_, KafkaBody>("n0", send).await.unwrap();
+        }
+
+        let poll = poll(&[("a", 1), ("c", 0)]);
+        match client.rpc("n0", poll).await.unwrap() {
+            KafkaBody::PollOk { msgs } => {
+                assert_eq!(
+                    msgs,
+                    HashMap::from([("a".into(), vec![(1, 102), (2, 103)])])
+                );
+            }
+            other => panic!("unexpected reply {other:?}"),
+        }
+
+        let commit = KafkaBody::CommitOffsets {
+            offsets: HashMap::from([("a".into(), 2), ("b".into(), 0)]),
+            group: None,
+        };
+        client.rpc::<_, KafkaBody>("n0", commit).await.unwrap();
+        let list = KafkaBody::ListCommittedOffsets {
+            keys: vec!["a".into(), "b".into(), "c".into()],
+            group: None,
+        };
+        match client.rpc("n0", list).await.unwrap() {
+            KafkaBody::ListCommittedOffsetsOk { offsets } => {
+                assert_eq!(offsets, HashMap::from([("a".into(), 2), ("b".into(), 0)]));
+            }
+            other => panic!("unexpected reply {other:?}"),
+        }
+    });
+}
+
+#[test]
+fn kafka_polls_long_logs() {
+    let data_dir = env::temp_dir().join(format!("kafka-long-logs-{}", process::id()));
+    let _ = fs::remove_dir_all(&data_dir);
+    // Logs on disk only keep their latest segments in memory, and read the others back.
+    for data_dir in [None, Some(data_dir.clone())] {
+        let kafka = KafkaConfig {
+            data_dir,
+            ..KafkaConfig::default()
+        };
+        sim::run(async {
+            let cluster = kafka_cluster(Config::default(), kafka).await;
+            let client = cluster.client();
+            // Long enough to span a few segments of the log.
+            kafka_send(&client, "n0", "a", 0..5000).await;
+            for from in [0, 1000, 1023, 1024, 1030, 2047, 4990, 5000] {
+                let expected: Vec<_> = (from..5000).take(20).map(|o| (o, o)).collect();
+                assert_eq!(
+                    kafka_poll(&client, "n0", "a", from).await,
+                    expected,
+                    "poll from {from}"
+                );
+            }
+        });
+    }
+    fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[test]
+fn kafka_concurrent_sends_and_polls() {
+    sim::run(async {
+        let cluster = kafka_cluster(Config::default(), KafkaConfig::default()).await;
+        let clients: Vec<_> = (0..4).map(|_| cluster.client()).collect();
+        let workers = clients.iter().enumerate().map(|(i, client)| async move {
+            for n in 0..50 {
+                let key = (n % 3).to_string();
+                let send = send(&key, (i * 1000 + n) as u64);
+                client.rpc::<_, KafkaBody>("n0", send).await.unwrap();
+                let poll = poll(&[(&key, n as u64 / 2)]);
+                client.rpc::<_, KafkaBody>("n0", poll).await.unwrap();
+            }
+        });
+        futures::future::join_all(workers).await;
+
+        let anomalies = checker::kafka::check(&History::from_trace(&cluster.trace()));
+        assert!(anomalies.is_empty(), "{anomalies:?}");
+    });
+}
+
+#[test]
+fn kafka_multi_node() {
+    sim::run(async {
+        let config = Config {
+            node_count: 3,
+            ..Config::default()
+        };
+        let cluster = kafka_cluster(config, KafkaConfig::default()).await;
+        let clients: Vec<_> = (0..4).map(|_| cluster.client()).collect();
+        // Every client sends through one node and polls through another.
+        let workers = clients.iter().enumerate().map(|(i, client)| async move {
+            let (sender, poller) = (format!("n{}", i % 3), format!("n{}", (i + 1) % 3));
+            for n in 0..50 {
+                let key = (n % 3).to_string();
+                let send = send(&key, (i * 1000 + n) as u64);
+                client.rpc::<_, KafkaBody>(&sender, send).await.unwrap();
+                let poll = poll(&[(&key, n as u64 / 2)]);
+                client.rpc::<_, KafkaBody>(&poller, poll).await.unwrap();
+            }
+        });
+        futures::future::join_all(workers).await;
+
+        // Every node sees the same committed offsets.
+        let commit = KafkaBody::CommitOffsets {
+            offsets: HashMap::from([("0".into(), 10)]),
+            group: None,
+        };
+        clients[0].rpc::<_, KafkaBody>("n1", commit).await.unwrap();
+        for id in cluster.node_ids() {
+            let list = KafkaBody::ListCommittedOffsets {
+                keys: vec!["0".into(), "1".into()],
+                group: None,
+            };
+            match clients[0].rpc(id, list).await.unwrap() {
+                KafkaBody::ListCommittedOffsetsOk { offsets } => {
+                    assert_eq!(offsets, HashMap::from([("0".into(), 10)]));
+                }
+                other => panic!("unexpected reply {other:?}"),
+            }
+        }
+
+        let trace = cluster.trace();
+        // Keys are served by their owners, without any round-trips to the KV store.
+        assert!(trace.iter().all(|event| event.message.dst != "lin-kv"));
+        let anomalies = checker::kafka::check(&History::from_trace(&trace));
+        assert!(anomalies.is_empty(), "{anomalies:?}");
+    });
+}
+
+/// Send to a replicated kafka cluster while its leaders are cut off one after another,
+/// check that no acknowledged send is lost, and return the trace.
+fn kafka_failovers(config: Config) -> Vec<Event> {
+    sim::run(async {
+        let kafka = KafkaConfig {
+            replication: 3,
+            ..KafkaConfig::default()
+        };
+        let cluster = kafka_cluster(config, kafka).await;
+        let clients: Vec<_> = (0..3).map(|_| cluster.client()).collect();
+        // Clients only talk to nodes that aren't cut off, like they would behind a load balancer.
+        let isolated = Cell::new("");
+        let acked = RefCell::new(Vec::new());
+        let workers = clients.iter().enumerate().map(|(i, client)| {
+            let (isolated, acked) = (&isolated, &acked);
+            async move {
+                for n in 0..120 {
+                    let key = (n % 3).to_string();
+                    let node = (0..3)
+                        .map(|j| format!("n{}", (i + n + j) % 3))
+                        .find(|id| id != isolated.get())
+                        .unwrap();
+                    let msg = (i * 1000 + n) as u64;
+                    if let Ok(KafkaBody::SendOk { offset }) =
+                        client.rpc(&node, send(&key, msg)).await
+                    {
+                        acked.borrow_mut().push((key, offset, msg));
+                    }
+                    if n % 10 == 0 {
+                        // Requests for several keys are routed to several leaders.
+                        let poll = poll(&[("0", 0), ("1", 0), ("2", 0)]);
+                        let _ = client.rpc::<_, KafkaBody>(&node, poll).await;
+                        let commit = KafkaBody::CommitOffsets {
+                            offsets: HashMap::from([
+                                ("0".into(), 1),
+                                ("1".into(), 1),
+                                ("2".into(), 1),
+                            ]),
+                            group: None,
+                        };
+                        let _ = client.rpc::<_, KafkaBody>(&node, commit).await;
+                    }
+                    time::sleep(Duration::from_millis(20)).await;
+                }
+            }
+        });
+        let nemesis = async {
+            for id in ["n0", "n1"] {
+                time::sleep(Duration::from_millis(300)).await;
+                let others: Vec<&str> = ["n0", "n1", "n2"]
+                    .into_iter()
+                    .filter(|&other| other != id)
+                    .collect();
+                isolated.set(id);
+                cluster.partition(&[&[id], &others]);
+                time::sleep(Duration::from_millis(1500)).await;
+                cluster.heal();
+                isolated.set("");
+            }
+        };
+        futures::future::join(futures::future::join_all(workers), nemesis).await;
+        time::sleep(Duration::from_secs(1)).await;
+
+        // Every acknowledged send survived the failovers.
+        let client = cluster.client();
+        let mut logs: HashMap<String, Vec<(u64, u64)>> = HashMap::new();
+        for key in ["0", "1", "2"] {
+            let log = logs.entry(key.into()).or_default();
+            loop {
+                let from = log.last().map_or(0, |&(offset, _)| offset + 1);
+                match kafka_poll(&client, "n2", key, from).await {
+                    msgs if !msgs.is_empty() => log.extend(msgs),
+                    _ => break,
+                }
+            }
+        }
+        for (key, offset, msg) in acked.into_inner() {
+            assert_eq!(logs[&key].get(offset as usize), Some(&(offset, msg)));
+        }
+        // And none of them was appended twice.
+        for (key, log) in &logs {
+            let msgs: HashSet<u64> = log.iter().map(|&(_, msg)| msg).collect();
+            assert_eq!(
+                msgs.len(),
+                log.len(),
+                "duplicate messages in {key}: {log:?}"
+            );
+        }
+
+        let trace = cluster.trace();
+        let took_over = trace.iter().any(|event| {
+            event.message.dst == "lin-kv" && event.message.body.inner["to"]["epoch"] != 0
+        });
+        assert!(took_over, "no replica took over");
+        let rejoined = trace.iter().any(|event| {
+            let cas = &event.message.body.inner;
+            event.message.dst == "lin-kv"
+                && cas["to"]["isr"].as_array().map(Vec::len)
+                    > cas["from"]["isr"].as_array().map(Vec::len)
+        });
+        assert!(rejoined, "no replica rejoined the ISR");
+        let anomalies = checker::kafka::check(&History::from_trace(&trace));
+        assert!(anomalies.is_empty(), "{anomalies:?}");
+        trace
+    })
+}
+
+#[test]
+fn kafka_replicas_take_over_from_partitioned_leaders() {
+    kafka_failovers(Config {
+        node_count: 3,
+        ..Config::default()
+    });
+}
+
+#[test]
+fn kafka_replays_from_seed() {
+    let config = Config {
+        node_count: 3,
+        seed: 7,
+        ..Config::default()
+    };
+    assert_eq!(kafka_failovers(config.clone()), kafka_failovers(config));
+}
+
+/// Send `msgs` to `key` through `node`, and return their offsets.
+async fn kafka_send(client: &Client, node: &str, key: &str, msgs: Range<u64>) -> Vec<u64> {
+    let mut offsets = Vec::new();
+    for msg in msgs {
+        match client.rpc(node, send(key, msg)).await.unwrap() {
+            KafkaBody::SendOk { offset } => offsets.push(offset),
+            other => panic!("unexpected reply {other:?}"),
+        }
+    }
+    offsets
+}
+
+/// Send message `seq` of idempotent producer `producer_id` to key `a` through `n1`.
+async fn kafka_produce(client: &Client, producer_id: u64, seq: u64) -> Result<u64, ErrorCode> {
+    let send = KafkaBody::Send {
+        key: "a".into(),
+        msg: producer_id * 100 + seq,
+        msg_key: None,
+        producer_id: Some(producer_id),
+        seq: Some(seq),
+    };
+    match client.rpc("n1", send).await {
+        Ok(KafkaBody::SendOk { offset }) => Ok(offset),
+        Ok(other) => panic!("unexpected reply {other:?}"),
+        Err(e) => Err(e.code),
+    }
+}
+
+/// Poll `key` from offset `from` through `node`.
+async fn kafka_poll(client: &Client, node: &str, key: &str, from: u64) -> Vec<(u64, u64)> {
+    match client.rpc(node, poll(&[(key, from)])).await.unwrap() {
+        KafkaBody::PollOk { mut msgs } => msgs.remove(key).unwrap_or_default(),
+        other => panic!("unexpected reply {other:?}"),
+    }
+}
+
+/// All files below `dir` whose name ends with `suffix`.
+fn files_ending_with(dir: &Path, suffix: &str) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            files.extend(files_ending_with(&path, suffix));
+        } else if path.to_string_lossy().ends_with(suffix) {
+            files.push(path);
+        }
+    }
+    files
+}
+
+#[test]
+fn kafka_recovers_logs_from_disk() {
+    let data_dir = env::temp_dir().join(format!("kafka-recovery-{}", process::id()));
+    let _ = fs::remove_dir_all(&data_dir);
+    let config = Config {
+        node_count: 2,
+        ..Config::default()
+    };
+    let kafka = KafkaConfig {
+        data_dir: Some(data_dir.clone()),
+        ..KafkaConfig::default()
+    };
+
+    sim::run(async {
+        let cluster = kafka_cluster(config.clone(), kafka.clone()).await;
+        let client = cluster.client();
+        assert_eq!(
+            kafka_send(&client, "n0", "a", 0..30).await,
+            Vec::from_iter(0..30)
+        );
+        assert_eq!(
+            kafka_send(&client, "n1", "b", 100..105).await,
+            Vec::from_iter(0..5)
+        );
+        // Any key goes, even an empty one.
+        assert_eq!(
+            kafka_send(&client, "n0", "", 200..203).await,
+            Vec::from_iter(0..3)
+        );
+        let commit = KafkaBody::CommitOffsets {
+            offsets: HashMap::from([("a".into(), 12)]),
+            group: None,
+        };
+        client.rpc::<_, KafkaBody>("n1", commit).await.unwrap();
+    });
+
+    // The node crashed halfway through appending a message.
+    for file in files_ending_with(&data_dir, ".log") {
+        let mut file = fs::OpenOptions::new().append(true).open(file).unwrap();
+        file.write_all(&[0xff; 17]).unwrap();
+    }
+
+    // The same nodes start again, with the same IDs.
+    sim::run(async {
+        let cluster = kafka_cluster(config.clone(), kafka.clone()).await;
+        let client = cluster.client();
+        let poll = poll(&[("a", 25), ("b", 0), ("", 0)]);
+        match client.rpc("n0", poll).await.unwrap() {
+            KafkaBody::PollOk { msgs } => {
+                let a: Vec<_> = (25..30).map(|o| (o, o)).collect();
+                let b: Vec<_> = (0..5).map(|o| (o, 100 + o)).collect();
+                let empty: Vec<_> = (0..3).map(|o| (o, 200 + o)).collect();
+                let expected =
+                    HashMap::from([("a".into(), a), ("b".into(), b), ("".into(), empty)]);
+                assert_eq!(msgs, expected);
+            }
+            other => panic!("unexpected reply {other:?}"),
+        }
+        let list = KafkaBody::ListCommittedOffsets {
+            keys: vec!["a".into()],
+            group: None,
+        };
+        match client.rpc("n1", list).await.unwrap() {
+            KafkaBody::ListCommittedOffsetsOk { offsets } => {
+                assert_eq!(offsets, HashMap::from([("a".into(), 12)]));
+            }
+            other => panic!("unexpected reply {other:?}"),
+        }
+        // The torn write is gone, and the log carries on where it left off.
+        assert_eq!(kafka_send(&client, "n1", "a", 30..31).await, [30]);
+    });
+    fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[test]
+fn kafka_idempotent_producers() {
+    let data_dir = env::temp_dir().join(format!("kafka-producers-{}", process::id()));
+    let _ = fs::remove_dir_all(&data_dir);
+    let config = Config {
+        node_count: 2,
+        ..Config::default()
+    };
+    let kafka = KafkaConfig {
+        data_dir: Some(data_dir.clone()),
+        ..KafkaConfig::default()
+    };
+    sim::run(async {
+        let cluster = kafka_cluster(config.clone(), kafka.clone()).await;
+        let client = &cluster.client();
+        for seq in 0..3 {
+            assert_eq!(kafka_produce(client, 1, seq).await, Ok(seq));
+        }
+        // Retries get the original offset, whoever else sent in between.
+        assert_eq!(kafka_produce(client, 2, 0).await, Ok(3));
+        assert_eq!(kafka_produce(client, 1, 1).await, Ok(1));
+        assert_eq!(kafka_produce(client, 1, 2).await, Ok(2));
+        for seq in 3..10 {
+            assert_eq!(kafka_produce(client, 1, seq).await, Ok(seq + 1));
+        }
+        // Only the latest few sends of every producer are remembered.
+        assert_eq!(
+            kafka_produce(client, 1, 1).await,
+            Err(ErrorCode::PreconditionFailed)
+        );
+        let polled = kafka_poll(client, "n0", "a", 0).await;
+        let mut expected: Vec<_> = (0..3).map(|seq| 100 + seq).collect();
+        expected.push(200);
+        expected.extend((3..10).map(|seq| 100 + seq));
+        assert_eq!(polled, (0..).zip(expected).collect::<Vec<_>>());
+    });
+
+    // Producers are recognized by the messages they left in the log.
+    sim::run(async {
+        let cluster = kafka_cluster(config.clone(), kafka.clone()).await;
+        let client = &cluster.client();
+        assert_eq!(kafka_produce(client, 1, 9).await, Ok(10));
+        assert_eq!(kafka_produce(client, 1, 10).await, Ok(11));
+    });
+    fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[test]
+fn kafka_poll_limits_and_long_polls() {
+    sim::run(async {
+        let config = Config {
+            node_count: 2,
+            ..Config::default()
+        };
+        let kafka = KafkaConfig {
+            poll: PollLimits {
+                max_messages: 10,
+                max_bytes: None,
+                max_wait: Duration::from_millis(400),
+            },
+            ..KafkaConfig::default()
+        };
+        let cluster = kafka_cluster(config, kafka).await;
+        let client = &cluster.client();
+        kafka_send(client, "n0", "a", 0..30).await;
+        let poll = |node: &'static str, from, max_messages, max_bytes, max_wait_ms| async move {
+            let poll = KafkaBody::Poll {
+                offsets: HashMap::from([("a".into(), from)]),
+                max_messages,
+                max_bytes,
+                max_wait_ms,
+            };
+            match client.rpc(node, poll).await.unwrap() {
+                KafkaBody::PollOk { mut msgs } => msgs.remove("a").unwrap_or_default(),
+                other => panic!("unexpected reply {other:?}"),
+            }
+        };
+
+        for node in ["n0", "n1"] {
+            // Polls can ask for fewer messages than the node's limit, but not for more.
+            assert_eq!(poll(node, 0, Some(5), None, None).await.len(), 5);
+            assert_eq!(poll(node, 0, Some(50), None, None).await.len(), 10);
+            // Every `[offset,msg]` is 5 bytes, but there's always at least one.
+            assert_eq!(poll(node, 0, None, Some(12), None).await, [(0, 0), (1, 1)]);
+            assert_eq!(poll(node, 0, None, Some(1), None).await, [(0, 0)]);
+        }
+
+        // Long polls return as soon as there's a new message, through the leader or not.
+        for (msg, node) in (30..).zip(["n0", "n1"]) {
+            let start = Instant::now();
+            let send = async {
+                time::sleep(Duration::from_millis(200)).await;
+                kafka_send(client, "n0", "a", msg..msg + 1).await
+            };
+            let (polled, _) = tokio::join!(poll(node, msg, None, None, Some(300)), send);
+            assert_eq!(polled, [(msg, msg)]);
+            assert!(start.elapsed() < Duration::from_millis(300), "{node}");
+        }
+
+        // Otherwise they give up after the wait, which is capped by the node's limit.
+        let start = Instant::now();
+        assert!(poll("n1", 32, None, None, Some(5000)).await.is_empty());
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_millis(400) && waited < Duration::from_millis(600));
+    });
+}
+
+#[test]
+fn kafka_consumer_groups() {
+    sim::run(async {
+        let config = Config {
+            node_count: 3,
+            ..Config::default()
+        };
+        let cluster = kafka_cluster(config, KafkaConfig::default()).await;
+        let client = &cluster.client();
+
+        // Every group has committed offsets of its own.
+        for (group, offset) in [(Some("g1"), 5), (Some("g2"), 2), (None, 1)] {
+            let commit = KafkaBody::CommitOffsets {
+                offsets: HashMap::from([("a".into(), offset)]),
+                group: group.map(Into::into),
+            };
+            client.rpc::<_, KafkaBody>("n0", commit).await.unwrap();
+        }
+        let expected = [
+            (Some("g1"), Some(5)),
+            (Some("g2"), Some(2)),
+            (None, Some(1)),
+            (Some("g3"), None),
+        ];
+        for (group, expected) in expected {
+            let list = KafkaBody::ListCommittedOffsets {
+                keys: vec!["a".into()],
+                group: group.map(Into::into),
+            };
+            match client.rpc("n2", list).await.unwrap() {
+                KafkaBody::ListCommittedOffsetsOk { offsets } => {
+                    assert_eq!(offsets.get("a").copied(), expected, "group {group:?}");
+                }
+                other => panic!("unexpected reply {other:?}"),
+            }
+        }
+
+        // Members can talk to any node, which forwards to the group's coordinator.
+        let keys = BTreeSet::from(["a", "b", "c", "d"].map(String::from));
+        let heartbeat = |member: &str, node: &str| {
+            let heartbeat = KafkaBody::Heartbeat {
+                group: "g".into(),
+                member: member.into(),
+                keys: keys.clone(),
+            };
+            let node = node.to_owned();
+            async move {
+                match client.rpc(&node, heartbeat).await.unwrap() {
+                    KafkaBody::HeartbeatOk {
+                        generation,
+                        assigned,
+                    } => (generation, assigned),
+                    other => panic!("unexpected reply {other:?}"),
+                }
+            }
+        };
+        let all: Vec<String> = keys.iter().cloned().collect();
+        assert_eq!(heartbeat("m1", "n0").await, (1, all.clone()));
+
+        // A second member joins, and the keys are split between the two.
+        let (generation, m2) = heartbeat("m2", "n1").await;
+        assert_eq!(generation, 2);
+        let (generation, mut m1) = heartbeat("m1", "n2").await;
+        assert_eq!((generation, m1.len(), m2.len()), (2, 2, 2));
+        m1.extend(m2);
+        m1.sort();
+        assert_eq!(m1, all);
+
+        // Members that leave hand their keys back right away.
+        let leave = KafkaBody::LeaveGroup {
+            group: "g".into(),
+            member: "m2".into(),
+        };
+        client.rpc::<_, KafkaBody>("n1", leave).await.unwrap();
+        assert_eq!(heartbeat("m1", "n0").await, (3, all.clone()));
+
+        // Members that stop sending heartbeats lose them once their session times out.
+        assert_eq!(heartbeat("m2", "n0").await.0, 4);
+        for _ in 0..4 {
+            time::sleep(Duration::from_secs(1)).await;
+            heartbeat("m1", "n1").await;
+        }
+        assert_eq!(heartbeat("m1", "n0").await, (5, all));
+    });
+}
+
+#[test]
+fn kafka_retention_deletes_committed_segments() {
+    sim::run(async {
+        let config = Config {
+            node_count: 2,
+            ..Config::default()
+        };
+        let kafka = KafkaConfig {
+            replication: 2,
+            retention: Retention {
+                time: Some(Duration::from_secs(10)),
+                messages: Some(1000),
+                uncommitted: false,
+            },
+            ..KafkaConfig::default()
+        };
+        let cluster = kafka_cluster(config, kafka).await;
+        let client = &cluster.client();
+        kafka_send(client, "n0", "a", 0..2500).await;
+        let commit = |offset| async move {
+            let commit = KafkaBody::CommitOffsets {
+                offsets: HashMap::from([("a".into(), offset)]),
+                group: None,
+            };
+            client.rpc::<_, KafkaBody>("n1", commit).await.unwrap();
+            time::sleep(Duration::from_secs(2)).await;
+        };
+
+        // Nobody has committed anything yet, so everything is kept.
+        time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(kafka_poll(client, "n0", "a", 0).await[0], (0, 0));
+
+        // The first segment has enough messages after it, the second one hasn't been committed.
+        commit(2000).await;
+        for node in ["n0", "n1"] {
+            assert_eq!(kafka_poll(client, node, "a", 0).await[0], (1024, 1024));
+        }
+
+        // Once it's committed, the second segment expires, but the last one stays.
+        commit(2499).await;
+        time::sleep(Duration::from_secs(10)).await;
+        for node in ["n0", "n1"] {
+            assert_eq!(kafka_poll(client, node, "a", 0).await[0], (2048, 2048));
+        }
+        assert_eq!(kafka_send(client, "n0", "a", 2500..2501).await, [2500]);
+    });
+}
+
+#[test]
+fn kafka_compaction_keeps_the_latest_message_per_key() {
+    let data_dir = env::temp_dir().join(format!("kafka-compaction-{}", process::id()));
+    let _ = fs::remove_dir_all(&data_dir);
+    let kafka = KafkaConfig {
+        data_dir: Some(data_dir.clone()),
+        compaction: true,
+        ..KafkaConfig::default()
+    };
+    // Messages without a key are kept, and so are the last ones for each of the keys,
+    // which are all in the last segment.
+    let expected: Vec<_> = (0..5).chain(2048..2063).map(|o| (o, o)).collect();
+
+    sim::run(async {
+        let cluster = kafka_cluster(Config::default(), kafka.clone()).await;
+        let client = cluster.client();
+        kafka_send(&client, "n0", "a", 0..5).await;
+        for msg in 5..2100 {
+            let send = KafkaBody::Send {
+                key: "a".into(),
+                msg,
+                msg_key: Some(msg % 10),
+                producer_id: None,
+                seq: None,
+            };
+            client.rpc::<_, KafkaBody>("n0", send).await.unwrap();
+        }
+        time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(kafka_poll(&client, "n0", "a", 0).await, expected);
+        assert_eq!(kafka_poll(&client, "n0", "a", 5).await[0], (2048, 2048));
+    });
+
+    // The compacted log is what's on disk.
+    sim::run(async {
+        let cluster = kafka_cluster(Config::default(), kafka.clone()).await;
+        let client = cluster.client();
+        assert_eq!(kafka_poll(&client, "n0", "a", 0).await, expected);
+        assert_eq!(kafka_send(&client, "n0", "a", 0..1).await, [2100]);
+    });
+    fs::remove_dir_all(&data_dir).unwrap();
+}
+
+#[test]
+fn kafka_transactions() {
+    sim::run(async {
+        let config = Config {
+            node_count: 3,
+            ..Config::default()
+        };
+        let kafka = KafkaConfig {
+            replication: 2,
+            txn_timeout: Duration::from_secs(2),
+            ..KafkaConfig::default()
+        };
+        let cluster = kafka_cluster(config, kafka).await;
+        let client = &cluster.client();
+        let msgs = |msgs: &[(&str, u64)]| {
+            msgs.iter()
+                .map(|&(key, msg)| (key.to_owned(), msg))
+                .collect::<Vec<_>>()
+        };
+
+        // Committed transactions are polled like any other messages, minus their markers.
+        kafka_send(client, "n0", "a", 0..1).await;
+        let send = KafkaBody::SendTxn {
+            msgs: msgs(&[("a", 1), ("b", 2), ("a", 3)]),
+        };
+        match client.rpc("n2", send).await.unwrap() {
+            KafkaBody::SendTxnOk { offsets } => assert_eq!(offsets, [1, 0, 2]),
+            other => panic!("unexpected reply {other:?}"),
+        }
+        assert_eq!(kafka_send(client, "n1", "a", 4..5).await, [4]);
+        for node in ["n0", "n1", "n2"] {
+            assert_eq!(
+                kafka_poll(client, node, "a", 0).await,
+                [(0, 0), (1, 1), (2, 3), (4, 4)]
+            );
+            assert_eq!(kafka_poll(client, node, "b", 0).await, [(0, 2)]);
+        }
+
+        // Aborted transactions are skipped, and can't be appended to anymore.
+        let append = KafkaBody::AppendTxn {
+            txn: 1000,
+            msgs: msgs(&[("a", 5), ("b", 6)]),
+        };
+        client
+            .rpc::<_, KafkaBody>("n0", append.clone())
+            .await
+            .unwrap();
+        let end = KafkaBody::EndTxn {
+            txn: 1000,
+            keys: vec!["a".into(), "b".into()],
+            marker: Marker::Abort,
+        };
+        client.rpc::<_, KafkaBody>("n0", end).await.unwrap();
+        let appended = client.rpc::<_, KafkaBody>("n0", append).await;
+        assert_eq!(appended.unwrap_err().code, ErrorCode::Abort);
+        assert_eq!(kafka_send(client, "n0", "b", 7..8).await, [4]);
+        assert_eq!(kafka_poll(client, "n1", "b", 1).await, [(4, 7)]);
+
+        // Nothing after an open transaction is polled, until its leader gives up on it.
+        let append = KafkaBody::AppendTxn {
+            txn: 1001,
+            msgs: msgs(&[("a", 8)]),
+        };
+        client.rpc::<_, KafkaBody>("n0", append).await.unwrap();
+        assert_eq!(kafka_send(client, "n0", "a", 9..10).await, [8]);
+        assert!(kafka_poll(client, "n0", "a", 5).await.is_empty());
+        time::sleep(Duration::from_secs(4)).await;
+        for node in ["n0", "n1", "n2"] {
+            assert_eq!(kafka_poll(client, node, "a", 5).await, [(8, 9)]);
+        }
+    });
+}
+
+/// Run `txn` on `node` and return the ops with the values of the reads filled in.
+async fn txn(client: &Client, node: &str, txn: &[MicroOp]) -> Vec<MicroOp> {
+    let request = TxnBody::Txn { txn: txn.to_vec() };
+    match client.rpc(node, request).await.unwrap() {
+        TxnBody::TxnOk { txn } => txn,
+        other => panic!("unexpected reply {other:?}"),
+    }
+}
+
+#[test]
+fn txn_rw_register_single_node() {
+    sim::run(async {
+        let config = Config {
+            node_count: 1,
+            ..Config::default()
+        };
+        let cluster = Cluster::start(config, TxnRwRegister::default)
+            .await
+            .unwrap();
+        let client = &cluster.client();
+        let (r, w) = (OpKind::Read, OpKind::Write);
+
+        // Reads see the writes before them, in the same transaction or an earlier one.
+        let ops = [
+            MicroOp(r, 1, None),
+            MicroOp(w, 1, Some(10)),
+            MicroOp(r, 1, None),
+        ];
+        assert_eq!(
+            txn(client, "n0", &ops).await,
+            [
+                MicroOp(r, 1, None),
+                MicroOp(w, 1, Some(10)),
+                MicroOp(r, 1, Some(10))
+            ]
+        );
+        let ops = [MicroOp(w, 2, Some(20)), MicroOp(r, 1, None)];
+        assert_eq!(
+            txn(client, "n0", &ops).await,
+            [MicroOp(w, 2, Some(20)), MicroOp(r, 1, Some(10))]
+        );
+
+        // The wire format is Maelstrom's.
+        let request = json!({"type": "txn", "txn": [["r", 2, null], ["w", 3, 30]]});
+        let reply: serde_json::Value = client.rpc("n0", request).await.unwrap();
+        assert_eq!(reply["txn"], json!([["r", 2, 20], ["w", 3, 30]]));
+
+        let request = TxnBody::Txn {
+            txn: vec![MicroOp(w, 1, None)],
+        };
+        let reply = client.rpc::<_, TxnBody>("n0", request).await;
+        assert_eq!(reply.unwrap_err().code, ErrorCode::MalformedRequest);
+    });
+}
+
+#[test]
+fn txn_rw_register_replicates_through_partitions() {
+    sim::run(async {
+        let config = Config {
+            node_count: 3,
+            ..Config::default()
+        };
+        let cluster = Cluster::start(config, TxnRwRegister::default)
+            .await
+            .unwrap();
+        let client = &cluster.client();
+        let (r, w) = (OpKind::Read, OpKind::Write);
+        let read_all = [MicroOp(r, 1, None), MicroOp(r, 2, None)];
+
+        // Both sides of a partition keep accepting writes, even to the same key.
+        cluster.partition(&[&["n0"], &["n1", "n2"]]);
+        txn(client, "n0", &[MicroOp(w, 1, Some(10))]).await;
+        let ops = [MicroOp(w, 1, Some(11)), MicroOp(w, 2, Some(21))];
+        txn(client, "n1", &ops).await;
+        time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(
+            txn(client, "n2", &read_all).await,
+            [MicroOp(r, 1, Some(11)), MicroOp(r, 2, Some(21))]
+        );
+        assert_eq!(
+            txn(client, "n0", &read_all).await,
+            [MicroOp(r, 1, Some(10)), MicroOp(r, 2, None)]
+        );
+
+        // Once they can talk again, all nodes agree on which write wins.
+        cluster.heal();
+        time::sleep(Duration::from_secs(5)).await;
+        for node in cluster.node_ids() {
+            assert_eq!(
+                txn(client, node, &read_all).await,
+                [MicroOp(r, 1, Some(11)), MicroOp(r, 2, Some(21))],
+                "{node}"
+            );
+        }
+    });
+}
+
+#[test]
+fn txn_rw_register_read_committed_orders_dependent_writes() {
+    let (r, w) = (OpKind::Read, OpKind::Write);
+    // `n1` reads a write of `n0` and then overwrites another key of the same transaction.
+    // Unless its clock has caught up, `n0`'s write wins, so each transaction would come before the other.
+    let final_value = |consistency| {
+        sim::run(async move {
+            let config = Config {
+                node_count: 2,
+                ..Config::default()
+            };
+            let txn_config = TxnConfig { consistency };
+            let cluster = Cluster::start(config, || TxnRwRegister::new(txn_config.clone()))
+                .await
+                .unwrap();
+            let client = &cluster.client();
+            for value in 0..5 {
+                txn(client, "n0", &[MicroOp(w, 3, Some(value))]).await;
+            }
+            let ops = [MicroOp(w, 1, Some(1)), MicroOp(w, 2, Some(1))];
+            txn(client, "n0", &ops).await;
+            time::sleep(Duration::from_millis(500)).await;
+            let ops = [MicroOp(r, 1, None), MicroOp(w, 2, Some(2))];
+            assert_eq!(txn(client, "n1", &ops).await[0], MicroOp(r, 1, Some(1)));
+            time::sleep(Duration::from_millis(500)).await;
+            let mut values = Vec::new();
+            for node in cluster.node_ids() {
+                values.push(txn(client, node, &[MicroOp(r, 2, None)]).await[0]);
+            }
+            assert_eq!(values[0], values[1]);
+            values[0].2
+        })
+    };
+    assert_eq!(final_value(Consistency::ReadCommitted), Some(2));
+    assert_eq!(final_value(Consistency::ReadUncommitted), Some(1));
+}
+
+#[test]
+fn txn_rw_register_serializable_transactions_form_a_chain() {
+    sim::run(async {
+        let config = Config {
+            node_count: 2,
+            ..Config::default()
+        };
+        let txn_config = TxnConfig {
+            consistency: Consistency::Serializable,
+        };
+        let cluster = Cluster::start(config, || TxnRwRegister::new(txn_config.clone()))
+            .await
+            .unwrap();
+        let (r, w) = (OpKind::Read, OpKind::Write);
+        let clients: Vec<_> = (0..3).map(|_| cluster.client()).collect();
+
+        // Concurrent transactions that read and overwrite the same key, through both nodes.
+        let txns = clients.iter().enumerate().map(|(i, client)| async move {
+            let mut committed = Vec::new();
+            for j in 0..5 {
+                let node = format!("n{}", (i + j) % 2);
+                let value = (i * 10 + j) as u64;
+                let request = TxnBody::Txn {
+                    txn: vec![MicroOp(r, 1, None), MicroOp(w, 1, Some(value))],
+                };
+                match client.rpc::<_, TxnBody>(&node, request).await {
+                    Ok(TxnBody::TxnOk { txn }) => committed.push((txn[0].2, value)),
+                    Ok(other) => panic!("unexpected reply {other:?}"),
+                    Err(e) => assert_eq!(e.code, ErrorCode::TxnConflict),
+                }
+            }
+            committed
+        });
+        let committed: Vec<_> = futures::future::join_all(txns)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        let count = committed.len();
+        let committed: HashMap<Option<u64>, u64> = committed.into_iter().collect();
+        assert_eq!(
+            committed.len(),
+            count,
+            "two transactions read the same value"
+        );
+
+        // Each committed transaction read what the one before it wrote.
+        let mut latest = None;
+        for _ in 0..committed.len() {
+            latest = Some(committed[&latest]);
+        }
+        assert!(latest.is_some());
+        for node in cluster.node_ids() {
+            let read = txn(&clients[0], node, &[MicroOp(r, 1, None)]).await;
+            assert_eq!(read, [MicroOp(r, 1, latest)]);
+        }
+    });
+}
+
+#[test]
+fn txn_rw_register_serializable_transactions_on_different_keys_all_commit() {
+    sim::run(async {
+        let config = Config {
+            node_count: 2,
+            ..Config::default()
+        };
+        let txn_config = TxnConfig {
+            consistency: Consistency::Serializable,
+        };
+        let cluster = Cluster::start(config, || TxnRwRegister::new(txn_config.clone()))
+            .await
+            .unwrap();
+        let (r, w) = (OpKind::Read, OpKind::Write);
+        let clients: Vec<_> = (0..3).map(|_| cluster.client()).collect();
+
+        // Every client counts up its own key through both nodes. The transactions race for the
+        // root, but the ones that lose to a transaction on another key go again on top of it.
+        let txns = clients.iter().enumerate().map(|(i, client)| async move {
+            let key = i as u64;
+            for j in 0..5 {
+                let node = format!("n{}", (i + j) % 2);
+                let ops = [MicroOp(r, key, None), MicroOp(w, key, Some(j as u64 + 1))];
+                let done = txn(client, &node, &ops).await;
+                assert_eq!(done[0], MicroOp(r, key, (j > 0).then_some(j as u64)));
+            }
+        });
+        futures::future::join_all(txns).await;
+
+        for node in cluster.node_ids() {
+            let keys: Vec<_> = (0..3).map(|key| MicroOp(r, key, None)).collect();
+            let read = txn(&clients[0], node, &keys).await;
+            let expected: Vec<_> = (0..3).map(|key| MicroOp(r, key, Some(5))).collect();
+            assert_eq!(read, expected);
+        }
+    });
+}
+
+#[test]
+fn txn_rw_register_serializable_failed_transactions_never_commit() {
+    sim::run(async {
+        let config = Config {
+            node_count: 2,
+            drop_rate: 0.2,
+            ..Config::default()
+        };
+        let txn_config = TxnConfig {
+            consistency: Consistency::Serializable,
+        };
+        let cluster = Cluster::start(config, || TxnRwRegister::new(txn_config.clone()))
+            .await
+            .unwrap();
+        let (r, w) = (OpKind::Read, OpKind::Write);
+        let clients: Vec<_> = (0..4).map(|_| cluster.client()).collect();
+
+        // Lost replies from lin-kv must not turn commits into conflicts or other definite failures.
+        let failed = RefCell::new(BTreeSet::new());
+        let read = RefCell::new(BTreeSet::new());
+        let txns = clients.iter().enumerate().map(|(i, client)| {
+            let (failed, read) = (&failed, &read);
+            async move {
+                for j in 0..20 {
+                    let node = format!("n{}", (i + j) % 2);
+                    let value = (i * 100 + j) as u64;
+                    let request = TxnBody::Txn {
+                        txn: vec![MicroOp(r, 1, None), MicroOp(w, 1, Some(value))],
+                    };
+                    match client.rpc::<_, TxnBody>(&node, request).await {
+                        Ok(TxnBody::TxnOk { txn }) => read.borrow_mut().extend(txn[0].2),
+                        Ok(other) => panic!("unexpected reply {other:?}"),
+                        Err(e) if e.is_definite() => {
+                            failed.borrow_mut().insert(value);
+                        }
+                        Err(_) => {}
+                    }
+                }
+            }
+        });
+        futures::future::join_all(txns).await;
+        cluster.set_drop_rate(0.0);
+        let last = txn(&clients[0], "n0", &[MicroOp(r, 1, None)]).await;
+        read.borrow_mut().extend(last[0].2);
+
+        let failed = failed.into_inner();
+        assert!(!failed.is_empty());
+        let committed: Vec<_> = read.into_inner().intersection(&failed).copied().collect();
+        assert!(
+            committed.is_empty(),
+            "failed transactions committed: {committed:?}"
+        );
+    });
+}
+
+#[test]
+fn lin_kv_is_linearizable() {
+    sim::run(async {
+        let cluster = Cluster::start(Config::default(), Kafka::default)
+            .await
+            .unwrap();
+        let clients: Vec<_> = (0..5).map(|_| cluster.client()).collect();
+        let workers = clients.iter().enumerate().map(|(i, client)| async move {
+            for n in 0..30u64 {
+                let key = json!(n % 2);
+                let request = match (i as u64 + n) % 3 {
+                    0 => KvBody::Read { key },
+                    1 => KvBody::Write {
+                        key,
+                        value: json!(n % 4),
+                    },
+                    _ => KvBody::Cas {
+                        key,
+                        from: json!(n % 4),
+                        to: json!((n + 1) % 4),
+                        create_if_not_exists: false,
+                    },
+                };
+                // Failed CAS operations and reads of missing keys are part of the test.
+                let _ = client.rpc::<_, KvBody>("lin-kv", request).await;
+            }
+        });
+        futures::future::join_all(workers).await;
+
+        let history = History::from_trace(&cluster.trace());
+        assert!(history.ops().iter().any(|op| op.kind == OpType::Fail));
+        let anomalies = checker::register::check(&history);
+        assert!(anomalies.is_empty(), "{anomalies:?}");
+    });
+}
+
+#[test]
+fn unknown_requests_are_not_supported() {
+    sim::run(async {
+        let cluster = Cluster::start(Config::default(), Kafka::default)
+            .await
+            .unwrap();
+        let result = cluster
+            .client()
+            .rpc::<_, KafkaBody>("n0", GCounterBody::Read)
+            .await;
+        assert!(matches!(
+            result,
+            Err(Error {
+                code: ErrorCode::NotSupported,
+                ..
+            })
+        ));
+    });
+}